@@ -0,0 +1,103 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Measures the throughput of parsing and accounting records, without Kafka.
+// Run with
+//
+//     cargo bench --bench process [-- FILE]
+//
+// where FILE optionally holds captured records, one per line, to use instead
+// of the built-in samples.
+
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dcache_kafka_exporter::collector::{Collector, Config, Origin};
+use prometheus_exporter::prometheus::Registry;
+
+const SAMPLES : &[&str] = &[
+    concat!(
+        r#"{"msgType":"transfer","billingPath":"/p","cellDomain":"dom1","cellName":"pool1","#,
+        r#""cellType":"pool","date":"2024-01-01T00:00:00.000+01:00","fileSize":1000,"#,
+        r#""initiator":"door:WebDAV-host@webdavDomain:AAX:123","isP2p":false,"#,
+        r#""isWrite":"write","localEndpoint":"10.0.0.1:2000","meanReadBandwidth":null,"#,
+        r#""meanWriteBandwidth":1.5e6,"pnfsid":"0000ABC","#,
+        r#""protocolInfo":{"host":"192.168.1.17","port":1234,"protocol":"Http","#,
+        r#""versionMajor":1,"versionMinor":1},"queuingTime":12,"readActive":null,"#,
+        r#""session":"s","transferTime":2000,"storageInfo":"atlas:default@osm","#,
+        r#""transferSize":1000,"transferPath":"/p","writeActive":"PT1.5S","#,
+        r#""subject":["UidPrincipal[1000]","GidPrincipal[1000,primary]"],"status":{"code":0,"#,
+        r#""msg":""}}"#,
+    ),
+    concat!(
+        r#"{"msgType":"request","billingPath":"/p","cellDomain":"dom1","#,
+        r#""cellName":"WebDAV-host","cellType":"door","client":"192.168.1.17","#,
+        r#""clientChain":"192.168.1.17,10.0.0.5","fileSize":1000,"mappedGID":1000,"#,
+        r#""mappedUID":1000,"moverInfo":null,"owner":null,"pnfsid":"0000ABC","queuingTime":3,"#,
+        r#""session":"s","sessionDuration":500,"status":{"code":10001,"msg":"No such file"},"#,
+        r#""storageInfo":null,"subject":["UidPrincipal[1000]"],"transferPath":"/p"}"#,
+    ),
+    concat!(
+        r#"{"msgType":"store","billingPath":"/p","cellDomain":"dom1","cellName":"pool1","#,
+        r#""cellType":"pool","date":"d","fileSize":1000,"hsm":{"instance":"osm","#,
+        r#""provider":"script","type":"osm"},"locations":["osm://a"],"status":{"code":0,"#,
+        r#""msg":""},"queuingTime":3,"transferTime":5000,"session":"s","#,
+        r#""storageInfo":"atlas:tape@osm","pnfsid":"0000DEF","transaction":"t1"}"#,
+    ),
+    concat!(
+        r#"{"msgType":"restore","billingPath":"/p","cellDomain":"dom1","cellName":"pool1","#,
+        r#""cellType":"pool","date":"d","fileSize":1000,"hsm":{"instance":"osm","#,
+        r#""provider":"script","type":"osm"},"locations":[],"status":{"code":10022,"#,
+        r#""msg":"Failed to restore 0000DEF from tape: timeout after 3600 s"},"#,
+        r#""queuingTime":3,"transferTime":5000,"session":"s","storageInfo":"atlas:tape@osm","#,
+        r#""pnfsid":"0000DEF","transaction":"t2"}"#,
+    ),
+    concat!(
+        r#"{"msgType":"remove","billingPath":"/p","cellDomain":"dom1","#,
+        r#""cellName":"PnfsManager","cellType":"PnfsManager","fileSize":1000,"#,
+        r#""pnfsid":"0000DEF","queuingTime":0,"session":"s","status":{"code":0,"msg":""},"#,
+        r#""storageInfo":null,"subject":[],"transaction":null}"#,
+    ),
+];
+
+const MEASUREMENT_TIME : Duration = Duration::from_secs(3);
+
+fn main() {
+    // Skip the --bench flag passed by cargo.
+    let records = match env::args().skip(1).find(|arg| !arg.starts_with("--")) {
+        Some(path) => {
+            let contents = fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("cannot read {}: {}", path, err));
+            contents.lines().filter(|line| !line.trim().is_empty()).map(String::from).collect()
+        }
+        None => { SAMPLES.iter().map(|record| record.to_string()).collect::<Vec<_>>() }
+    };
+    let collector = Collector::new(Config::default(), &Registry::new()).unwrap();
+    let origin = Origin {topic: Arc::from("billing"), partition: 0, offset: 0, key: String::new()};
+    let start = Instant::now();
+    let mut count = 0;
+    while start.elapsed() < MEASUREMENT_TIME {
+        for record in &records {
+            black_box(collector.process_message(black_box(record), &origin));
+        }
+        count += records.len();
+    }
+    let elapsed = start.elapsed();
+    println!("process_message: {} records in {:.2?}, {:.0} records/s, {:.2?} per record",
+             count, elapsed, count as f64 / elapsed.as_secs_f64(), elapsed / count as u32);
+}
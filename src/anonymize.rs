@@ -0,0 +1,79 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::LazyLock;
+use regex::{Captures, Regex};
+
+// The pattern of IPv4 and IPv6 addresses, shared with the message simplifier.
+// IPv6 addresses must have all eight groups or a "::", so that times and MAC
+// addresses are not taken for them.  This is a macro so that it can be
+// embedded in other patterns with concat!.
+macro_rules! ip_pattern {
+    () => {
+        concat!(
+            r"(?:[0-9A-Fa-f]{0,4}:){2,6}\d{1,3}(?:\.\d{1,3}){3}\b",
+            r"|\b\d{1,3}(?:\.\d{1,3}){3}\b",
+            r"|(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}",
+            r"|(?:[0-9A-Fa-f]{1,4}:){1,6}(?::[0-9A-Fa-f]{1,4}){1,6}",
+            r"|(?:[0-9A-Fa-f]{1,4}:){1,7}:",
+            r"|::(?:[0-9A-Fa-f]{1,4}(?::[0-9A-Fa-f]{1,4}){0,6})?",
+        )
+    };
+}
+pub(crate) use ip_pattern;
+
+// Candidates for IP addresses.  The pattern is still loose about the number
+// of groups, so matches are only replaced if they parse as addresses.
+static IP_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(ip_pattern!()).unwrap());
+
+// Zeroes the host part of an address, keeping the /24 network for IPv4 and
+// the /48 network for IPv6.
+pub fn anonymize_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            IpAddr::V4(Ipv4Addr::from(addr.to_bits() & !0xff))
+        }
+        IpAddr::V6(addr) => {
+            IpAddr::V6(Ipv6Addr::from(addr.to_bits() & !((1u128 << 80) - 1)))
+        }
+    }
+}
+
+// Anonymizes all IP addresses found in a text.
+pub fn anonymize_ips(text: &str) -> Cow<'_, str> {
+    IP_RE.replace_all(text, |caps: &Captures| {
+        match caps[0].parse::<IpAddr>() {
+            Ok(addr) => { anonymize_ip(addr).to_string() }
+            Err(_) => { caps[0].to_string() }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_and_ipv6() {
+        assert_eq!(anonymize_ips("from 192.0.2.17:2811 to 2001:db8:1:2::17"),
+                   "from 192.0.2.0:2811 to 2001:db8:1::");
+        assert_eq!(anonymize_ips("[::1]:22"), "[::]:22");
+        assert_eq!(anonymize_ips("at 12:30:45 from 00:1a:2b:3c:4d:5e"),
+                   "at 12:30:45 from 00:1a:2b:3c:4d:5e");
+    }
+}
@@ -14,6 +14,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+// The records are deserialized in full, though not all fields are used.
+//
+// Some fields are also accepted under alternative spellings, since their
+// names have varied between dCache versions:
+//
+//   - "queueTime" for "queuingTime",
+//   - "pnfsId" for "pnfsid",
+//   - "mappedGid" and "mappedUid" for "mappedGID" and "mappedUID",
+//   - "isP2P" for "isP2p".
+
 use std::fmt;
 use serde::{de, Deserialize};
 
@@ -34,7 +44,11 @@ impl fmt::Display for Direction {
 
 #[derive(Deserialize)]
 #[serde(field_identifier, rename_all = "camelCase")]
-enum DirectionField {IsP2p, IsWrite}
+enum DirectionField {
+    #[serde(alias = "isP2P")]
+    IsP2p,
+    IsWrite,
+}
 
 impl<'de> Deserialize<'de> for Direction {
 
@@ -54,7 +68,7 @@ impl<'de> Deserialize<'de> for Direction {
                 where V: de::MapAccess<'de>
             {
                 let mut is_p2p = None;
-                let mut is_write = None;
+                let mut is_write: Option<String> = None;
                 while let Some(key) = map.next_key()? {
                     match key {
                         DirectionField::IsP2p => {
@@ -65,8 +79,10 @@ impl<'de> Deserialize<'de> for Direction {
                         }
                     }
                 }
-                match (is_p2p, is_write) {
-                    (Some(true), Some("read")) => { Ok(Direction::P2p) }
+                // Pool-to-pool transfers are accounted as P2p whether they
+                // are reported by the source or the destination pool.
+                match (is_p2p, is_write.as_deref()) {
+                    (Some(true), Some("read" | "write")) => { Ok(Direction::P2p) }
                     (Some(false), Some("read")) => { Ok(Direction::Read) }
                     (Some(false), Some("write")) => { Ok(Direction::Write) }
                     _ => {
@@ -75,15 +91,26 @@ impl<'de> Deserialize<'de> for Direction {
                 }
             }
         }
-        const FIELDS: &[&str] = &["isP2p", "isWrite"];
+        const FIELDS: &[&str] = &["isP2p", "isP2P", "isWrite"];
         deserializer.deserialize_struct("Direction", FIELDS, DirectionVisitor)
     }
 }
 
+// Deserializes a quantity for which dCache uses -1 to mean unknown.  Fields
+// using this are given with `default`, so that a missing value is also None.
+fn deserialize_unknown_as_none<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+    where D: de::Deserializer<'de>
+{
+    let value = i64::deserialize(deserializer)?;
+    Ok(u64::try_from(value).ok())
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ProtocolInfo {
+    #[allow(dead_code)]
     pub host: String,
+    #[allow(dead_code)]
     pub port: u32,
     pub protocol: String, // FIXME
     pub version_major: u32,
@@ -120,130 +147,405 @@ pub enum MoverInfo {
 
     #[serde(rename_all = "camelCase")]
     Transfer {
+        #[allow(dead_code)]
         #[serde(flatten)]
         cell: Cell,
+        #[allow(dead_code)]
         date: String, // FIXME
         #[serde(flatten)]
         direction: Direction,
+        #[allow(dead_code)]
         local_endpoint: String,
+        #[allow(dead_code)]
         mean_read_bandwidth: Option<f64>, // bytes/s
+        #[allow(dead_code)]
         mean_write_bandwidth: Option<f64>, // bytes/s
+        #[allow(dead_code)]
         protocol_info: ProtocolInfo,
-        queuing_time: u64,
+        #[allow(dead_code)]
+        #[serde(alias = "queueTime", default, deserialize_with = "deserialize_unknown_as_none")]
+        queuing_time: Option<u64>,
+        #[allow(dead_code)]
         read_active: Option<String>,
+        #[allow(dead_code)]
         read_idle: Option<String>,
+        #[allow(dead_code)]
         session: String,
+        #[allow(dead_code)]
         status: Status,
+        #[allow(dead_code)]
         transfer_path: String,
-        transfer_size: u64,
-        transfer_time: u64,
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        transfer_size: Option<u64>,
+        #[allow(dead_code)]
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        transfer_time: Option<u64>,
+        #[allow(dead_code)]
         version: String,
     },
 }
 
+// Used to inspect the type of records which could not be parsed as a Message.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageHeader {
+    pub msg_type: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase", tag = "msgType")]
 pub enum Message {
 
     #[serde(rename_all = "camelCase")]
     Remove {
+        #[allow(dead_code)]
         billing_path: String,
         #[serde(flatten)]
         cell: Cell,
-        file_size: u64,
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        file_size: Option<u64>,
+        #[serde(alias = "pnfsId")]
         pnfsid: String,
-        queuing_time: u64,
+        #[serde(alias = "queueTime", default, deserialize_with = "deserialize_unknown_as_none")]
+        queuing_time: Option<u64>,
+        #[allow(dead_code)]
         session: String,
         status: Status,
         storage_info: Option<String>, // present for pools, absent for doors
+        #[allow(dead_code)]
         subject: Vec<String>,
         transaction: Option<String>,
     },
 
     #[serde(rename_all = "camelCase")]
     Request {
+        #[allow(dead_code)]
         billing_path: String,
         #[serde(flatten)]
         cell: Cell,
+        #[allow(dead_code)]
         client: String,
         client_chain: String,
-        file_size: u64,
-        #[serde(rename = "mappedGID")]
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        file_size: Option<u64>,
+        #[allow(dead_code)]
+        #[serde(rename = "mappedGID", alias = "mappedGid")]
         mapped_gid: u32,
-        #[serde(rename = "mappedUID")]
+        #[allow(dead_code)]
+        #[serde(rename = "mappedUID", alias = "mappedUid")]
         mapped_uid: u32,
-        mover_info: Option<MoverInfo>,
+        mover_info: Option<Box<MoverInfo>>,
+        #[allow(dead_code)]
         owner: Option<String>,
+        #[serde(alias = "pnfsId")]
         pnfsid: Option<String>,
-        queuing_time: u64,
+        #[serde(alias = "queueTime", default, deserialize_with = "deserialize_unknown_as_none")]
+        queuing_time: Option<u64>,
+        #[allow(dead_code)]
         session: String,
-        session_duration: u64,
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        session_duration: Option<u64>,
         status: Status,
         storage_info: Option<String>, // may be missing when status.code != 0
         subject: Vec<String>,
+        #[allow(dead_code)]
         transfer_path: String,
     },
 
     #[serde(rename_all = "camelCase")]
     Restore {
+        #[allow(dead_code)]
         billing_path: String,
         #[serde(flatten)]
         cell: Cell,
+        #[allow(dead_code)]
         date: String,
-        file_size: u64,
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        file_size: Option<u64>,
         hsm: Hsm,
         locations: Vec<String>,
+        #[serde(alias = "pnfsId")]
         pnfsid: String,
-        queuing_time: u64,
+        #[serde(alias = "queueTime", default, deserialize_with = "deserialize_unknown_as_none")]
+        queuing_time: Option<u64>,
+        #[allow(dead_code)]
         session: String,
         status: Status,
         storage_info: String,
         transaction: String,
-        transfer_time: u64,
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        transfer_time: Option<u64>,
+        #[allow(dead_code)]
         version: String,
     },
 
     #[serde(rename_all = "camelCase")]
     Store {
+        #[allow(dead_code)]
         billing_path: String,
         #[serde(flatten)]
         cell: Cell,
+        #[allow(dead_code)]
         date: String, // FIXME
-        file_size: u64,
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        file_size: Option<u64>,
         hsm: Hsm,
         locations: Vec<String>,
         status: Status,
-        queuing_time: u64,
-        transfer_time: u64,
+        #[serde(alias = "queueTime", default, deserialize_with = "deserialize_unknown_as_none")]
+        queuing_time: Option<u64>,
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        transfer_time: Option<u64>,
+        #[allow(dead_code)]
         session: String,
         storage_info: String,
+        #[serde(alias = "pnfsId")]
         pnfsid: String,
         transaction: String,
     },
 
     #[serde(rename_all = "camelCase")]
     Transfer {
+        #[allow(dead_code)]
         billing_path: String,
         #[serde(flatten)]
         cell: Cell,
+        #[allow(dead_code)]
         date: String, // FIXME
-        file_size: u64,
+        #[allow(dead_code)]
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        file_size: Option<u64>,
         initiator: String,
         #[serde(flatten)]
         direction: Direction,
         local_endpoint: Option<String>,
         mean_read_bandwidth: Option<f64>, // bytes/s
         mean_write_bandwidth: Option<f64>, // bytes/s
+        #[serde(alias = "pnfsId")]
         pnfsid: String,
         protocol_info: ProtocolInfo,
-        queuing_time: u64,
+        #[serde(alias = "queueTime", default, deserialize_with = "deserialize_unknown_as_none")]
+        queuing_time: Option<u64>,
         read_active: Option<String>,
+        read_idle: Option<String>,
+        #[allow(dead_code)]
         session: String,
-        transfer_time: u64,
+        #[serde(default, deserialize_with = "deserialize_unknown_as_none")]
+        transfer_time: Option<u64>,
         storage_info: String,
         transfer_size: u64,
+        #[allow(dead_code)]
         transfer_path: String,
         write_active: Option<String>,
+        write_idle: Option<String>,
         subject: Vec<String>,
     },
 }
+
+impl Message {
+    pub fn cell(&self) -> &Cell {
+        match self {
+            Message::Remove {cell, ..} |
+            Message::Request {cell, ..} |
+            Message::Restore {cell, ..} |
+            Message::Store {cell, ..} |
+            Message::Transfer {cell, ..} => { cell }
+        }
+    }
+
+    pub fn queuing_time(&self) -> Option<u64> {
+        match self {
+            Message::Remove {queuing_time, ..} |
+            Message::Request {queuing_time, ..} |
+            Message::Restore {queuing_time, ..} |
+            Message::Store {queuing_time, ..} |
+            Message::Transfer {queuing_time, ..} => { *queuing_time }
+        }
+    }
+
+    pub fn pnfsid(&self) -> Option<&str> {
+        match self {
+            Message::Request {pnfsid, ..} => { pnfsid.as_deref() }
+            Message::Remove {pnfsid, ..} |
+            Message::Restore {pnfsid, ..} |
+            Message::Store {pnfsid, ..} |
+            Message::Transfer {pnfsid, ..} => { Some(pnfsid) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direction(json: &str) -> Result<Direction, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn direction_p2p() {
+        let read = direction(r#"{"isP2p": true, "isWrite": "read"}"#).unwrap();
+        let write = direction(r#"{"isP2p": true, "isWrite": "write"}"#).unwrap();
+        assert!(matches!(read, Direction::P2p));
+        assert!(matches!(write, Direction::P2p));
+    }
+
+    #[test]
+    fn direction_read_write() {
+        let read = direction(r#"{"isP2p": false, "isWrite": "read"}"#).unwrap();
+        let write = direction(r#"{"isWrite": "write", "isP2p": false}"#).unwrap();
+        assert!(matches!(read, Direction::Read));
+        assert!(matches!(write, Direction::Write));
+    }
+
+    #[test]
+    fn direction_p2p_alias() {
+        let p2p = direction(r#"{"isP2P": true, "isWrite": "read"}"#).unwrap();
+        let read = direction(r#"{"isP2P": false, "isWrite": "read"}"#).unwrap();
+        assert!(matches!(p2p, Direction::P2p));
+        assert!(matches!(read, Direction::Read));
+    }
+
+    // Records of each type in which the fields with alternative spellings are
+    // named PNFSID, QUEUING_TIME, MAPPED_GID, and MAPPED_UID.
+    const RECORDS : &[&str] = &[
+        r#"{"msgType": "remove", "billingPath": "/", "cellName": "PnfsManager",
+            "cellType": "PnfsManager", "cellDomain": "namespaceDomain", "fileSize": 1000,
+            "PNFSID": "0000ABCD", "QUEUING_TIME": 0, "session": "s", "storageInfo": null,
+            "status": {"code": 0, "msg": ""}, "subject": [], "transaction": null}"#,
+        r#"{"msgType": "request", "billingPath": "/", "cellName": "WebDAV-host",
+            "cellType": "door", "cellDomain": "webdavDomain", "client": "192.0.2.1",
+            "clientChain": "192.0.2.1", "fileSize": 1000, "MAPPED_GID": 1000,
+            "MAPPED_UID": 1000, "moverInfo": null, "owner": null, "PNFSID": "0000ABCD",
+            "QUEUING_TIME": 3, "session": "s", "sessionDuration": 500,
+            "status": {"code": 0, "msg": ""}, "storageInfo": null, "subject": [],
+            "transferPath": "/data/file"}"#,
+        r#"{"msgType": "restore", "billingPath": "/", "cellName": "pool1", "cellType": "pool",
+            "cellDomain": "poolDomain", "date": "d", "fileSize": 1000,
+            "hsm": {"instance": "osm", "provider": "script", "type": "osm"},
+            "locations": ["osm://a"], "PNFSID": "0000ABCD", "QUEUING_TIME": 3,
+            "session": "s", "status": {"code": 0, "msg": ""}, "storageInfo": "a:b@osm",
+            "transaction": "t", "transferTime": 5000, "version": "1"}"#,
+        r#"{"msgType": "store", "billingPath": "/", "cellName": "pool1", "cellType": "pool",
+            "cellDomain": "poolDomain", "date": "d", "fileSize": 1000,
+            "hsm": {"instance": "osm", "provider": "script", "type": "osm"},
+            "locations": ["osm://a"], "PNFSID": "0000ABCD", "QUEUING_TIME": 3,
+            "session": "s", "status": {"code": 0, "msg": ""}, "storageInfo": "a:b@osm",
+            "transaction": "t", "transferTime": 5000}"#,
+        r#"{"msgType": "transfer", "billingPath": "/", "cellName": "pool1", "cellType": "pool",
+            "cellDomain": "poolDomain", "date": "d", "fileSize": 1000,
+            "initiator": "door:WebDAV-host@webdavDomain:1", "isP2p": false,
+            "isWrite": "read", "localEndpoint": null, "meanReadBandwidth": 1.5e6,
+            "meanWriteBandwidth": null, "PNFSID": "0000ABCD",
+            "protocolInfo": {"host": "192.0.2.1", "port": 1234, "protocol": "Http",
+                             "versionMajor": 1, "versionMinor": 1},
+            "QUEUING_TIME": 3, "readActive": "PT1S", "readIdle": null, "session": "s",
+            "transferTime": 2000, "storageInfo": "a:b@osm", "transferSize": 1000,
+            "transferPath": "/data/file", "writeActive": null, "writeIdle": null,
+            "subject": []}"#,
+    ];
+
+    fn parse_with(record: &str, spellings: &[(&str, &str)]) -> Message {
+        let mut record = record.to_string();
+        for (placeholder, name) in spellings {
+            record = record.replace(placeholder, name);
+        }
+        serde_json::from_str(&record).unwrap()
+    }
+
+    #[test]
+    fn alternative_spellings() {
+        for record in RECORDS {
+            let current = parse_with(record, &[
+                ("PNFSID", "pnfsid"), ("QUEUING_TIME", "queuingTime"),
+                ("MAPPED_GID", "mappedGID"), ("MAPPED_UID", "mappedUID"),
+            ]);
+            let alternative = parse_with(record, &[
+                ("PNFSID", "pnfsId"), ("QUEUING_TIME", "queueTime"),
+                ("MAPPED_GID", "mappedGid"), ("MAPPED_UID", "mappedUid"),
+            ]);
+            assert_eq!(current.pnfsid(), Some("0000ABCD"));
+            assert!(current.queuing_time().is_some());
+            assert_eq!(format!("{:?}", current), format!("{:?}", alternative));
+        }
+    }
+
+    // One of the records above with the current spellings, as JSON.
+    fn record(msg_type: &str) -> serde_json::Value {
+        let record = RECORDS.iter()
+            .find(|record| record.contains(&format!(r#""msgType": "{}""#, msg_type)))
+            .unwrap()
+            .replace("PNFSID", "pnfsid").replace("QUEUING_TIME", "queuingTime")
+            .replace("MAPPED_GID", "mappedGID").replace("MAPPED_UID", "mappedUID");
+        serde_json::from_str(&record).unwrap()
+    }
+
+    // Parses one of the records above with a field set to the given value,
+    // or removed if none.
+    fn parse_setting(msg_type: &str, field: &str, value: Option<i64>) -> Message {
+        let mut record = record(msg_type);
+        let fields = record.as_object_mut().unwrap();
+        match value {
+            Some(value) => { fields.insert(field.to_string(), value.into()); }
+            None => { fields.remove(field); }
+        }
+        serde_json::from_value(record).unwrap()
+    }
+
+    #[test]
+    fn unknown_file_size() {
+        for msg_type in ["remove", "request", "restore", "store", "transfer"] {
+            match parse_setting(msg_type, "fileSize", Some(-1)) {
+                Message::Remove {file_size, ..} | Message::Request {file_size, ..} |
+                Message::Restore {file_size, ..} | Message::Store {file_size, ..} |
+                Message::Transfer {file_size, ..} => { assert_eq!(file_size, None) }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_queuing_time() {
+        for msg_type in ["remove", "request", "restore", "store", "transfer"] {
+            let msg = parse_setting(msg_type, "queuingTime", Some(-1));
+            assert_eq!(msg.queuing_time(), None);
+        }
+    }
+
+    #[test]
+    fn unknown_transfer_time() {
+        for msg_type in ["restore", "store", "transfer"] {
+            match parse_setting(msg_type, "transferTime", Some(-1)) {
+                Message::Restore {transfer_time, ..} | Message::Store {transfer_time, ..} |
+                Message::Transfer {transfer_time, ..} => { assert_eq!(transfer_time, None) }
+                _ => { unreachable!() }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_session_duration() {
+        let Message::Request {session_duration, ..} =
+            parse_setting("request", "sessionDuration", Some(-1)) else { unreachable!() };
+        assert_eq!(session_duration, None);
+    }
+
+    #[test]
+    fn known_quantities() {
+        let Message::Request {file_size, session_duration, queuing_time, ..} =
+            parse_setting("request", "sessionDuration", Some(0)) else { unreachable!() };
+        assert_eq!((file_size, session_duration, queuing_time), (Some(1000), Some(0), Some(3)));
+    }
+
+    #[test]
+    fn missing_quantity() {
+        let Message::Transfer {file_size, transfer_time, ..} =
+            parse_setting("transfer", "transferTime", None) else { unreachable!() };
+        assert_eq!((file_size, transfer_time), (Some(1000), None));
+    }
+
+    #[test]
+    fn direction_unknown() {
+        assert!(direction(r#"{"isP2p": false, "isWrite": "append"}"#).is_err());
+        assert!(direction(r#"{"isP2p": false}"#).is_err());
+    }
+}
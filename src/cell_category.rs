@@ -0,0 +1,82 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Classification of the cell types of billing records into the categories
+// used for the cell_category label, which separates client-facing doors from
+// pools doing the actual IO.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use log::warn;
+
+// Cell types reported by various dCache versions.
+const BUILTIN_CATEGORIES : &[(&str, &str)] = &[
+    ("door", "door"),
+    ("dcap-door", "door"),
+    ("pool", "pool"),
+    ("Pool", "pool"),
+    ("PnfsManager", "other"),
+];
+
+pub struct CellCategories {
+    categories: HashMap<String, String>,
+    // Unknown cell types which have been logged.
+    unknown: Mutex<HashSet<String>>,
+}
+
+impl CellCategories {
+    pub fn builtin() -> CellCategories {
+        let categories = BUILTIN_CATEGORIES.iter()
+            .map(|(cell_type, category)| (cell_type.to_string(), category.to_string()))
+            .collect();
+        CellCategories {categories, unknown: Mutex::new(HashSet::new())}
+    }
+
+    // Adds or overrides categories from a file where each line holds a cell
+    // type and a category separated by whitespace.  Empty lines and lines
+    // starting with "#" are ignored.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((cell_type, category)) = line.split_once(char::is_whitespace) else {
+                return Err(format!("{}:{}: expected a cell type followed by a category",
+                                   path.display(), lineno + 1));
+            };
+            self.categories.insert(cell_type.to_string(), category.trim().to_string());
+        }
+        Ok(())
+    }
+
+    // The category of a cell type, or "other" if unknown.  Each unknown cell
+    // type is logged the first time it is seen.
+    pub fn category(&self, cell_type: &str) -> &str {
+        if let Some(category) = self.categories.get(cell_type) {
+            return category;
+        }
+        let mut unknown = self.unknown.lock().unwrap_or_else(PoisonError::into_inner);
+        if !unknown.contains(cell_type) {
+            warn!(cell_type; "Unknown cell type, categorized as \"other\".");
+            unknown.insert(cell_type.to_string());
+        }
+        "other"
+    }
+}
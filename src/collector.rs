@@ -14,92 +14,621 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use log::{warn};
+use std::borrow::Cow;
+use std::fmt;
+use std::str;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use flate2::read::GzDecoder;
+use log::{debug, warn};
+use rand::Rng;
+use regex::Regex;
 use prometheus_exporter::{
-    prometheus::core::{MetricVec, MetricVecBuilder},
     prometheus::{
-        IntCounter, register_int_counter,
-        IntCounterVec, register_int_counter_vec,
-        HistogramVec, register_histogram_vec,
+        self,
+        Gauge, GaugeVec, Histogram, IntCounter, IntGauge, IntGaugeVec, IntCounterVec, CounterVec,
+        HistogramVec, HistogramOpts, Opts, Registry,
+        core::Collector as _,
     }
 };
+use crate::anonymize::anonymize_ips;
 use crate::billing::*;
+use crate::cell_category::CellCategories;
+use crate::deadletter::DeadLetterFile;
+use crate::dedup::RecentIds;
+use crate::duration::parse_duration;
+use crate::exemplar::{self, ExemplarFamily};
+use crate::hsm_error::HsmErrorClasses;
+use crate::hyperloglog::HyperLogLog;
+use crate::message_simplifier::MessageRewriteRules;
+use crate::metric_names::MetricNames;
+use crate::rate::RateGaugeVec;
+use crate::recent::RecentMessages;
+use crate::schema::{MetricSchema, describe};
+use crate::source::{Batch, Record};
+use crate::state;
+use crate::status::StatusCategories;
+use crate::subject::{primary_vo, principal_types};
+use crate::summary::{SummaryOpts, SummaryVec, new_summary_vec};
+use crate::throttle::LogThrottle;
+
+pub struct Config {
+    pub metric_names: MetricNames,
+    pub ignored_msg_types: Vec<String>,
+    // Only count events from cell domains matching one of the includes, if
+    // any, and none of the excludes.
+    pub include_cell_domains: Vec<Regex>,
+    pub exclude_cell_domains: Vec<Regex>,
+    // Replace cell names fully matching a regular expression with the
+    // corresponding replacement in the cell_name label.  The first match
+    // applies.
+    pub cell_name_rewrites: Vec<(Regex, String)>,
+    pub message_rewrite_rules: MessageRewriteRules,
+    pub log_warn_rate: u32, // maximum number of parse warnings per minute
+    pub enable_size_histogram: bool,
+    pub enable_version_metric: bool,
+    pub enable_subject_type_metric: bool,
+    pub metric_style: MetricStyle,
+    pub summary_objectives: Vec<f64>,
+    pub disabled_metrics: Vec<String>,
+    pub anonymize_client_ip: bool,
+    pub enable_vo_label: bool,
+    pub enable_pool_label: bool,
+    pub enable_initiator_label: bool,
+    // Label all event metrics with the Kafka message key under this name.
+    pub key_label: Option<String>,
+    // Leave these labels out of all event metrics.
+    pub drop_labels: Vec<String>,
+    pub enable_exemplars: bool,
+    // Expose moving averages of the byte rates if true.
+    pub enable_rate_gauges: bool,
+    pub dedup_window: usize,
+    // Remove label combinations not seen for this long, if given.
+    pub label_ttl: Option<Duration>,
+    // Track the maximum queuing time per cell if true.
+    pub enable_max_recent_queuing: bool,
+    // Estimate the number of distinct PNFS IDs seen if true.
+    pub enable_unique_pnfsid_estimate: bool,
+    // Fold label combinations beyond this number into an overflow
+    // combination, if given.
+    pub max_series: Option<usize>,
+    // Truncate label values longer than this many characters, unless zero.
+    pub max_label_length: usize,
+    // The fraction of events for which to observe the *_seconds
+    // distributions.
+    pub histogram_sample_rate: f64,
+    // Add a status_category label according to these if given.
+    pub status_categories: Option<StatusCategories>,
+    // The categories of cell types for the cell_category label.
+    pub cell_categories: CellCategories,
+    // Add an hsm_error_class label to the failure counts of restores and
+    // stores according to these if given.
+    pub hsm_error_classes: Option<HsmErrorClasses>,
+    pub message_compression: MessageCompression,
+    // Drop messages larger than this many bytes before or after
+    // decompression, unless zero.
+    pub max_message_bytes: usize,
+    pub duration_unit: DurationUnit,
+    // Where to append records which cannot be parsed, if anywhere.
+    pub deadletter: Option<DeadLetterFile>,
+    // Where to keep the most recent messages, if anywhere.
+    pub debug_messages: Option<Arc<RecentMessages>>,
+    // Bucket overrides, where empty means to use the defaults.
+    pub long_duration_buckets: Vec<f64>,
+    pub short_duration_buckets: Vec<f64>,
+    pub transfer_rate_buckets: Vec<f64>,
+}
+
+// The defaults of the command line options, for use without it.
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            metric_names: MetricNames::new("dcache_kafka_".to_string()),
+            ignored_msg_types: vec!["hit".to_string(), "warning".to_string()],
+            include_cell_domains: vec![],
+            exclude_cell_domains: vec![],
+            cell_name_rewrites: vec![],
+            message_rewrite_rules: MessageRewriteRules::default(),
+            log_warn_rate: 10,
+            enable_size_histogram: false,
+            enable_version_metric: false,
+            enable_subject_type_metric: false,
+            metric_style: MetricStyle::Histogram,
+            summary_objectives: vec![0.5, 0.9, 0.99],
+            disabled_metrics: vec![],
+            anonymize_client_ip: false,
+            enable_vo_label: false,
+            enable_pool_label: false,
+            enable_initiator_label: false,
+            key_label: None,
+            drop_labels: vec![],
+            enable_exemplars: false,
+            enable_rate_gauges: false,
+            dedup_window: 0,
+            label_ttl: None,
+            enable_max_recent_queuing: false,
+            enable_unique_pnfsid_estimate: false,
+            max_series: None,
+            max_label_length: 256,
+            histogram_sample_rate: 1.0,
+            status_categories: None,
+            cell_categories: CellCategories::builtin(),
+            hsm_error_classes: None,
+            message_compression: MessageCompression::None,
+            max_message_bytes: 16 << 20,
+            duration_unit: DurationUnit::Ms,
+            deadletter: None,
+            debug_messages: None,
+            long_duration_buckets: vec![],
+            short_duration_buckets: vec![],
+            transfer_rate_buckets: vec![],
+        }
+    }
+}
+
+// The names accepted by --disable-metric, being either an event kind, which
+// covers all metrics of that kind, or the name of a single metric without
+// the prefix.
+pub const METRIC_FAMILIES : &[&str] = &[
+    "remove",
+    "remove_count",
+    "remove_bytes",
+    "remove_storage_class_bytes",
+    "remove_queuing_seconds",
+    "remove_file_size_bytes",
+    "request",
+    "request_count",
+    "request_without_mover_count",
+    "request_read_bytes",
+    "request_write_bytes",
+    "request_session_seconds",
+    "request_queuing_seconds",
+    "request_file_size_bytes",
+    "request_client_chain_depth",
+    "request_subject_type_count",
+    "restore",
+    "restore_count",
+    "restore_bytes",
+    "restore_success_bytes",
+    "restore_seconds",
+    "restore_queuing_seconds",
+    "restore_file_size_bytes",
+    "restore_location_count",
+    "restore_failed_count",
+    "store",
+    "store_count",
+    "store_bytes",
+    "store_success_bytes",
+    "store_seconds",
+    "store_queuing_seconds",
+    "store_file_size_bytes",
+    "store_location_count",
+    "store_failed_count",
+    "transfer",
+    "transfer_count",
+    "transfer_bytes",
+    "transfer_seconds",
+    "transfer_queuing_seconds",
+    "transfer_file_size_bytes",
+    "transfer_mean_read_bandwidth_bytes_per_second",
+    "transfer_mean_write_bandwidth_bytes_per_second",
+    "transfer_active_seconds",
+    "transfer_idle_seconds",
+    "transfer_version_count",
+    "transfer_protocol_count",
+    "transfer_total_queuing_seconds",
+    "transfer_total_active_seconds",
+    "transfer_total_idle_seconds",
+    "transfer_p2p_bytes",
+    "transfer_p2p_seconds",
+];
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum MetricStyle {Histogram, Summary}
+
+// Compression applied to message values by the producer, on top of any
+// compression done by Kafka.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum MessageCompression {None, Gzip}
+
+// The unit of the queuing times, transfer times, and session durations in the
+// records.  All dCache versions we know of use milliseconds.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum DurationUnit {Ms, S, Us}
+
+impl DurationUnit {
+    fn per_second(self) -> f64 {
+        match self {
+            DurationUnit::Ms => { 1e3 }
+            DurationUnit::S => { 1.0 }
+            DurationUnit::Us => { 1e6 }
+        }
+    }
+}
+
+// A metric for the distribution of observations, represented as selected by
+// the metric style.
+enum Distribution {
+    Histogram(HistogramVec, Option<Arc<ExemplarFamily>>),
+    Summary(SummaryVec),
+}
+
+impl Distribution {
+    fn observe(&self, labels: &[&str], v: f64) {
+        match self {
+            Distribution::Histogram(vec, _) => { vec.with_label_values(labels).observe(v) }
+            Distribution::Summary(vec) => { vec.with_label_values(labels).observe(v) }
+        }
+    }
+
+    fn remove(&self, labels: &[&str]) {
+        match self {
+            Distribution::Histogram(vec, exemplars) => {
+                let _ = vec.remove_label_values(labels);
+                if let Some(exemplars) = exemplars {
+                    exemplars.remove(labels);
+                }
+            }
+            Distribution::Summary(vec) => { let _ = vec.remove_label_values(labels); }
+        }
+    }
+
+    fn observe_with_exemplar(&self, labels: &[&str], v: f64, exemplar: &[(&str, &str)]) {
+        self.observe(labels, v);
+        if let Distribution::Histogram(_, Some(exemplars)) = self {
+            exemplars.observe(labels, v, exemplar);
+        }
+    }
+}
+
+// A failure to register a metric with the default registry, e.g. due to a
+// conflicting metric of the same name.
+#[derive(Debug)]
+pub struct RegisterError {
+    name: String,
+    error: prometheus::Error,
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot register the {} metric: {}", self.name, self.error)
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+// Registers a newly created metric with the given registry.
+fn registered<T>(registry: &Registry, name: &str, result: prometheus::Result<T>)
+    -> Result<T, RegisterError>
+    where T: prometheus::core::Collector + Clone + 'static
+{
+    result
+        .and_then(|metric| registry.register(Box::new(metric.clone())).map(|()| metric))
+        .map_err(|error| RegisterError {name: name.to_string(), error})
+}
+
+// Update helpers for metrics which may be disabled.
+
+fn add_rate(rate: &Option<RateGaugeVec>, labels: &[&str], bytes: u64) {
+    if let Some(rate) = rate {
+        rate.add(labels, bytes);
+    }
+}
+
+fn inc(vec: &Option<IntCounterVec>, labels: &[&str]) {
+    if let Some(vec) = vec {
+        vec.with_label_values(labels).inc();
+    }
+}
+
+fn inc_by(vec: &Option<IntCounterVec>, labels: &[&str], v: u64) {
+    if let Some(vec) = vec {
+        vec.with_label_values(labels).inc_by(v);
+    }
+}
+
+fn add(vec: &Option<CounterVec>, labels: &[&str], v: f64) {
+    if let Some(vec) = vec {
+        vec.with_label_values(labels).inc_by(v);
+    }
+}
+
+// The distribution if the observation is sampled, otherwise none.
+fn sampled(sample: bool, distribution: &Option<Distribution>) -> &Option<Distribution> {
+    if sample { distribution } else { &None }
+}
+
+fn observe(distribution: &Option<Distribution>, labels: &[&str], v: f64) {
+    if let Some(distribution) = distribution {
+        distribution.observe(labels, v);
+    }
+}
+
+fn observe_with_pnfsid(distribution: &Option<Distribution>, labels: &[&str], v: f64,
+                       pnfsid: &str) {
+    if let Some(distribution) = distribution {
+        distribution.observe_with_exemplar(labels, v, &[("pnfsid", pnfsid)]);
+    }
+}
+
+// A combination of label values of an event kind.
+type SeriesKey = (&'static str, Vec<String>);
+
+// The time each combination of label values was last seen, by event kind.
+type LabelLastSeen = HashMap<SeriesKey, Instant>;
+
+const OVERFLOW_LABEL_VALUE : &str = "<overflow>";
 
 pub struct Collector {
-    remove_count: IntCounterVec,
-    remove_bytes: IntCounterVec,
-    request_count: IntCounterVec,
-    request_session_seconds: HistogramVec,
-    restore_count: IntCounterVec,
-    restore_bytes: IntCounterVec,
-    restore_seconds: HistogramVec,
-    store_count: IntCounterVec,
-    store_bytes: IntCounterVec,
-    store_seconds: HistogramVec,
-    transfer_count: IntCounterVec,
-    transfer_bytes: IntCounterVec,
-    transfer_seconds: HistogramVec,
-    transfer_mean_read_bandwidth_bytes_per_second: HistogramVec,
-    transfer_mean_write_bandwidth_bytes_per_second: HistogramVec,
-    unparsed_count: IntCounter,
+    remove_count: Option<IntCounterVec>,
+    remove_bytes: Option<IntCounterVec>,
+    remove_storage_class_bytes: Option<IntCounterVec>,
+    remove_queuing_seconds: Option<Distribution>,
+    remove_file_size_bytes: Option<Distribution>,
+    request_count: Option<IntCounterVec>,
+    request_subject_type_count: Option<IntCounterVec>,
+    request_without_mover_count: Option<IntCounterVec>,
+    request_read_bytes: Option<IntCounterVec>,
+    request_write_bytes: Option<IntCounterVec>,
+    request_session_seconds: Option<Distribution>,
+    request_queuing_seconds: Option<Distribution>,
+    request_file_size_bytes: Option<Distribution>,
+    request_client_chain_depth: Option<Distribution>,
+    restore_count: Option<IntCounterVec>,
+    restore_bytes: Option<IntCounterVec>,
+    restore_success_bytes: Option<IntCounterVec>,
+    restore_seconds: Option<Distribution>,
+    restore_queuing_seconds: Option<Distribution>,
+    restore_file_size_bytes: Option<Distribution>,
+    restore_location_count: Option<IntCounterVec>,
+    restore_failed_count: Option<IntCounterVec>,
+    store_count: Option<IntCounterVec>,
+    store_bytes: Option<IntCounterVec>,
+    store_success_bytes: Option<IntCounterVec>,
+    store_seconds: Option<Distribution>,
+    store_queuing_seconds: Option<Distribution>,
+    store_file_size_bytes: Option<Distribution>,
+    store_location_count: Option<IntCounterVec>,
+    store_failed_count: Option<IntCounterVec>,
+    message_rewrite_rules: RwLock<MessageRewriteRules>,
+    message_rewrite_rule_count: IntGauge,
+    transfer_count: Option<IntCounterVec>,
+    transfer_bytes: Option<IntCounterVec>,
+    transfer_seconds: Option<Distribution>,
+    transfer_p2p_bytes: Option<IntCounterVec>,
+    transfer_p2p_seconds: Option<Distribution>,
+    transfer_queuing_seconds: Option<Distribution>,
+    transfer_file_size_bytes: Option<Distribution>,
+    transfer_mean_read_bandwidth_bytes_per_second: Option<Distribution>,
+    transfer_mean_write_bandwidth_bytes_per_second: Option<Distribution>,
+    transfer_active_seconds: Option<Distribution>,
+    transfer_idle_seconds: Option<Distribution>,
+    transfer_version_count: Option<IntCounterVec>,
+    transfer_protocol_count: Option<IntCounterVec>,
+    transfer_total_queuing_seconds: Option<CounterVec>,
+    transfer_total_active_seconds: Option<CounterVec>,
+    transfer_total_idle_seconds: Option<CounterVec>,
+    total_read_bytes: IntCounter,
+    total_write_bytes: IntCounter,
+    transfer_read_count: IntCounter,
+    transfer_write_count: IntCounter,
+    transfer_p2p_count: IntCounter,
+    processed_count: IntCounterVec,
+    unparsed_count: IntCounterVec,
+    missing_msgtype_count: IntCounter,
+    duplicate_count: IntCounter,
+    batch_count: IntCounter,
+    recent_transactions: Option<RecentIds>,
+    tape_retry_count: Option<IntCounterVec>,
+    label_last_seen: Option<Mutex<LabelLastSeen>>,
+    label_ttl: Duration,
+    max_recent_queuing_seconds: Option<Mutex<GaugeVec>>,
+    unique_pnfsids: Option<(Mutex<HyperLogLog>, Gauge)>,
+    transfer_bytes_rate: Option<RateGaugeVec>,
+    restore_bytes_rate: Option<RateGaugeVec>,
+    store_bytes_rate: Option<RateGaugeVec>,
+    known_series: Option<(usize, Mutex<HashSet<SeriesKey>>)>,
+    series_overflow_count: IntCounter,
+    ignored_count: IntCounterVec,
+    missing_storage_info_count: IntCounterVec,
+    transfer_initiator_count: IntCounterVec,
+    ignored_msg_types: Vec<String>,
+    include_cell_domains: Vec<Regex>,
+    exclude_cell_domains: Vec<Regex>,
+    cell_name_rewrites: Vec<(Regex, String)>,
+    filtered_count: IntCounter,
+    message_compression: MessageCompression,
+    max_message_bytes: usize,
+    oversize_dropped_count: IntCounter,
+    oversize_warning_throttle: LogThrottle,
+    tombstone_count: IntCounter,
+    consumed_count: IntCounterVec,
+    current_offset: IntGaugeVec,
+    message_bytes: Histogram,
+    duration_unit: DurationUnit,
+    deadletter: Option<DeadLetterFile>,
+    debug_messages: Option<Arc<RecentMessages>>,
+    anonymize_client_ip: bool,
+    status_categories: Option<StatusCategories>,
+    cell_categories: CellCategories,
+    hsm_error_classes: Option<HsmErrorClasses>,
+    enable_vo_label: bool,
+    enable_pool_label: bool,
+    enable_initiator_label: bool,
+    enable_key_label: bool,
+    // Which of the label values of each event kind to keep, if any are
+    // dropped.
+    kept_labels: HashMap<&'static str, Vec<bool>>,
+    // The names of the label values of each event kind.
+    label_names: HashMap<&'static str, Vec<String>>,
+    max_label_length: usize,
+    label_truncated_count: IntCounterVec,
+    label_truncation_warning_throttle: LogThrottle,
+    histogram_sample_rate: f64,
+    last_message_timestamp_seconds: Gauge,
+    process_duration_seconds: Histogram,
+    parse_warning_throttle: LogThrottle,
 }
 
 // For Message::Remove and Message::Request
-const REMOVE_REQUEST_LABELS : &[&str; 5] = &[
-    "cell_name", "cell_domain", "cell_type",
+const REMOVE_REQUEST_LABELS : &[&str; 6] = &[
+    "cell_name", "cell_domain", "cell_type", "cell_category",
     "status_code",
     "storage_info",
 ];
 
 // For Message::Restore and Message::Store
-const RESTORE_STORE_LABELS : &[&str; 8] = &[
-    "cell_name", "cell_domain", "cell_type",
+const RESTORE_STORE_LABELS : &[&str; 9] = &[
+    "cell_name", "cell_domain", "cell_type", "cell_category",
     "status_code",
     "storage_info",
     "hsm_instance", "hsm_provider", "hsm_type",
 ];
 
 // For Message::Transfer
-const TRANSFER_LABELS : &[&str; 5] = &[
-    "cell_name", "cell_domain", "cell_type",
+const TRANSFER_LABELS : &[&str; 6] = &[
+    "cell_name", "cell_domain", "cell_type", "cell_category",
     "direction",
     "storage_info",
 ];
 
-// Value projections corresponding to the above labels.
-fn proj<T : MetricVecBuilder>(vec: &MetricVec<T>, index: &Message) -> T::M {
-    match index {
-        Message::Remove {cell, status, storage_info, ..} |
-        Message::Request {cell, status, storage_info, ..} => {
-            let storage_info: &str = match storage_info {
-                None => { "" }
-                Some(s) => { s.as_str() }
-            };
-            vec.with_label_values(&[
-                cell.name.as_str(), cell.domain.as_str(), cell.type_.as_str(),
-                status.code.to_string().as_str(),
-                storage_info,
-            ])
-        }
-        Message::Restore {cell, status, storage_info, hsm, ..} |
-        Message::Store {cell, status, storage_info, hsm, ..} => {
-            vec.with_label_values(&[
-                cell.name.as_str(), cell.domain.as_str(), cell.type_.as_str(),
-                status.code.to_string().as_str(),
-                storage_info.as_str(),
-                hsm.instance.as_str(), hsm.provider.as_str(), hsm.type_.as_str(),
-            ])
-        }
-        Message::Transfer {cell, direction, storage_info, ..} => {
-            vec.with_label_values(&[
-                &cell.name[..], &cell.domain[..], &cell.type_[..],
-                &direction.to_string(),
-                storage_info.as_str(),
-            ])
+// For the pool-to-pool transfer metrics.  dCache reports these transfers
+// from the source pool, with the destination pool as the initiator.
+const TRANSFER_P2P_LABELS : &[&str; 2] = &["source_pool", "dest_pool"];
+
+// The labels which may be added to the above by options.
+const OPTIONAL_LABELS : &[&str; 6] =
+    &["status_category", "vo", "pool", "initiator", "error", "hsm_error_class"];
+
+// Whether a label can be left out by Config::drop_labels.  The error labels
+// are what the failure counts are about, as are the pool labels for the
+// pool-to-pool transfer metrics, so those should be disabled instead.
+pub fn is_droppable_label(name: &str) -> bool {
+    is_event_label(name) &&
+        !["error", "hsm_error_class"].contains(&name) &&
+        !TRANSFER_P2P_LABELS.contains(&name)
+}
+
+// Whether a label name is already used on some metric of events.
+pub fn is_event_label(name: &str) -> bool {
+    [&REMOVE_REQUEST_LABELS[..], RESTORE_STORE_LABELS, TRANSFER_LABELS, TRANSFER_P2P_LABELS,
+     OPTIONAL_LABELS]
+        .iter()
+        .any(|labels| labels.contains(&name))
+}
+
+// The status_code label value, avoiding an allocation for the most common
+// codes.
+fn status_code_label(code: u32) -> Cow<'static, str> {
+    match code {
+        0 => { "0".into() }
+        10001 => { "10001".into() }
+        10006 => { "10006".into() }
+        _ => { code.to_string().into() }
+    }
+}
+
+pub struct Totals {
+    pub processed: BTreeMap<String, u64>, // by message type
+    pub unparsed: u64,
+    pub last_message_timestamp: f64, // zero if none
+}
+
+// Where a record was consumed from, for correlating log entries with the
+// topic contents.
+pub struct Origin {
+    pub topic: Arc<str>,
+    pub partition: i32,
+    pub offset: i64,
+    // The message key if it is UTF-8 and used as a label, otherwise empty.
+    pub key: String,
+}
+
+// The reason label value of unparsed_count for a failure to parse a record.
+fn parse_error_reason(error: &serde_json::Error) -> &'static str {
+    match error.classify() {
+        serde_json::error::Category::Io => { "io" }
+        serde_json::error::Category::Syntax => { "syntax" }
+        serde_json::error::Category::Data => { "data" }
+        serde_json::error::Category::Eof => { "eof" }
+    }
+}
+
+// The host part of a local endpoint like "192.0.2.1:33115" or
+// "[2001:db8::1]:33115", leaving out the port which varies per transfer.
+fn endpoint_host(endpoint: &str) -> &str {
+    match endpoint.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => {
+            match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+                Some(host) => { host }
+                // An IPv6 address without a port.
+                None if host.contains(':') => { endpoint }
+                None => { host }
+            }
         }
+        _ => { endpoint }
+    }
+}
+
+// The number of hosts in a client chain, which dCache renders as a comma or
+// space separated list.
+fn client_chain_depth(client_chain: &str) -> usize {
+    client_chain.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|host| !host.is_empty())
+        .count()
+}
+
+// The name of the cell which initiated a transfer, given an initiator like
+// "door:WebDAV-dcache01@webdavDomain:1700000000000-42" or
+// "pool:pool1@poolDomain:1700000000000".
+fn initiator_cell(initiator: &str) -> Option<&str> {
+    let (_, rest) = initiator.split_once(':')?;
+    let (cell, _) = rest.split_once('@')?;
+    (!cell.is_empty()).then_some(cell)
+}
+
+// The type label of transfer_initiator_count, from the prefix of an
+// initiator.  Transfers initiated by doors serve clients, while transfers
+// initiated by pools are pool-to-pool copies, e.g. for hopping or
+// replication.  Other prefixes, like those of migration jobs and the pool
+// manager, are "system", and initiators without a prefix are "unknown".
+fn initiator_type(initiator: &str) -> &'static str {
+    match initiator.split_once(':') {
+        Some(("door", _)) => { "client" }
+        Some(("pool", _)) => { "pool" }
+        Some((prefix, _)) if !prefix.is_empty() => { "system" }
+        _ => { "unknown" }
+    }
+}
+
+// The storage group and storage class of a storage info of the form
+// GROUP:CLASS@HSM, with "unknown" for missing parts.
+fn storage_group_and_class(storage_info: Option<&str>) -> (&str, &str) {
+    let storage_class = storage_info.map(|s| s.split_once('@').map_or(s, |(class, _)| class));
+    match storage_class.and_then(|s| s.split_once(':')) {
+        Some((group, class)) if !group.is_empty() && !class.is_empty() => { (group, class) }
+        _ => { ("unknown", "unknown") }
     }
 }
 
+// The first characters of a label value followed by "…", if the value is
+// longer than `max_length` characters.
+fn truncate_label(value: &str, max_length: usize) -> Option<String> {
+    let mut boundaries = value.char_indices().map(|(i, _)| i).skip(max_length - 1);
+    let end = boundaries.next()?;
+    boundaries.next()?;
+    Some(format!("{}…", &value[..end]))
+}
+
+// The total of the durations which are present and parse, if any.
+fn sum_durations(durations: &[&Option<String>]) -> Option<f64> {
+    durations.iter()
+        .filter_map(|d| d.as_deref().and_then(parse_duration))
+        .reduce(|a, b| a + b)
+}
+
 // Buckets suitable for human presentation of durations which are typically
 // around a minute or longer.  This is a precise geometrical sequence which
 // aligns to 1 minute and 1 hour.
@@ -107,17 +636,17 @@ const LONG_DURATION_BUCKETS : [f64; 15] = [
     0.0010874632336580173,
     0.00425727462440863,
     0.016666666666666666,
-    0.065247794019481067,
+    0.06524779401948107,
     0.2554364774645177,
     1.0,
     3.9148676411688634,
     15.32618864787106,
     60.0,
     234.89205847013176,
-    919.57131887226399,
+    919.571318872264,
     3600.0,
     14093.523508207918,
-    55174.279132335789,
+    55174.27913233579,
     216000.0,
 ];
 
@@ -127,8 +656,8 @@ const SHORT_DURATION_BUCKETS : [f64; 13] = [
     0.001,
     0.0031622776601683794,
     0.01,
-    0.031622776601683791,
-    0.10000000000000001,
+    0.03162277660168379,
+    0.1,
     0.31622776601683794,
     1.0,
     3.1622776601683795,
@@ -157,132 +686,1867 @@ const TRANSFER_RATE_BUCKETS : [f64; 15] = [
     100000000000.0,
 ];
 
+// Buckets for the time spent processing a single message, which is expected
+// to be well below a millisecond.
+const PROCESSING_DURATION_BUCKETS : [f64; 13] = [
+    0.000001,
+    0.000003162277660168379,
+    0.00001,
+    0.00003162277660168379,
+    0.0001,
+    0.0003162277660168379,
+    0.001,
+    0.003162277660168379,
+    0.01,
+    0.03162277660168379,
+    0.1,
+    0.31622776601683794,
+    1.0,
+];
+
+// Buckets for message sizes from 256 B to 4 MiB in powers of 4.
+const MESSAGE_SIZE_BUCKETS : [f64; 8] = [
+    256.0,
+    1024.0,
+    4096.0,
+    16384.0,
+    65536.0,
+    262144.0,
+    1048576.0,
+    4194304.0,
+];
+
+// Buckets for the number of hosts in the client chain of a request.
+const CLIENT_CHAIN_DEPTH_BUCKETS : [f64; 7] = [0.0, 1.0, 2.0, 3.0, 4.0, 6.0, 8.0];
+
+// Buckets for file sizes from 1 KiB to 256 GiB in powers of 4.
+const FILE_SIZE_BUCKETS : [f64; 15] = [
+    1024.0,
+    4096.0,
+    16384.0,
+    65536.0,
+    262144.0,
+    1048576.0,
+    4194304.0,
+    16777216.0,
+    67108864.0,
+    268435456.0,
+    1073741824.0,
+    4294967296.0,
+    17179869184.0,
+    68719476736.0,
+    274877906944.0,
+];
+
 impl Collector {
-    pub fn new(metric_prefix : String) -> Collector {
-        Collector {
-            remove_count: register_int_counter_vec!(
-                metric_prefix.clone() + "remove_count",
+    // Creates the collector, registering its metrics with the given registry.
+    pub fn new(config : Config, registry : &Registry) -> Result<Collector, RegisterError> {
+        let metric_names = config.metric_names;
+        let mut remove_labels = REMOVE_REQUEST_LABELS.to_vec();
+        let mut request_labels = REMOVE_REQUEST_LABELS.to_vec();
+        let mut restore_store_labels = RESTORE_STORE_LABELS.to_vec();
+        let mut transfer_labels = TRANSFER_LABELS.to_vec();
+        if config.status_categories.is_some() {
+            remove_labels.push("status_category");
+            request_labels.push("status_category");
+            restore_store_labels.push("status_category");
+        }
+        if config.enable_vo_label {
+            request_labels.push("vo");
+            transfer_labels.push("vo");
+        }
+        let message_rewrite_rules = config.message_rewrite_rules;
+        let message_rewrite_rule_count = registered(
+            registry, "message_rewrite_rule_count",
+            IntGauge::new(
+                metric_names.get("message_rewrite_rule_count"),
+                "The number of rules used to simplify error messages."))?;
+        message_rewrite_rule_count.set(message_rewrite_rules.len() as i64);
+        if config.enable_pool_label {
+            transfer_labels.push("pool");
+        }
+        if config.enable_initiator_label {
+            transfer_labels.push("initiator");
+        }
+        if let Some(key_label) = &config.key_label {
+            for labels in [&mut remove_labels, &mut request_labels,
+                           &mut restore_store_labels, &mut transfer_labels] {
+                labels.push(key_label.as_str());
+            }
+        }
+        let mut kept_labels = HashMap::new();
+        if !config.drop_labels.is_empty() {
+            for (kinds, labels) in [(&["remove"][..], &mut remove_labels),
+                                    (&["request"], &mut request_labels),
+                                    (&["restore", "store"], &mut restore_store_labels),
+                                    (&["transfer"], &mut transfer_labels)] {
+                let kept: Vec<bool> = labels.iter()
+                    .map(|label| !config.drop_labels.iter().any(|d| d == label))
+                    .collect();
+                for kind in kinds {
+                    kept_labels.insert(*kind, kept.clone());
+                }
+                labels.retain(|label| !config.drop_labels.iter().any(|d| d == label));
+            }
+        }
+        let mut label_names = HashMap::new();
+        for (kinds, labels) in [(&["remove"][..], &remove_labels),
+                                (&["request"], &request_labels),
+                                (&["restore", "store"], &restore_store_labels),
+                                (&["transfer"], &transfer_labels)] {
+            for kind in kinds {
+                label_names.insert(*kind, labels.iter().map(|label| label.to_string()).collect());
+            }
+        }
+        let mut restore_store_failure_labels = restore_store_labels.clone();
+        restore_store_failure_labels.push("error");
+        if config.hsm_error_classes.is_some() {
+            restore_store_failure_labels.push("hsm_error_class");
+        }
+        for (kinds, labels) in [(&["restore_failed", "store_failed"][..],
+                                 &restore_store_failure_labels[..]),
+                                (&["transfer_p2p"], TRANSFER_P2P_LABELS),
+                                (&["max_recent_queuing"], &["cell_name", "cell_domain"]),
+                                (&["remove_storage_class"], &["storage_group", "storage_class"]),
+                                (&["transfer_protocol"], &["protocol", "version_major"]),
+                                (&["transfer_version"],
+                                 &["protocol", "version_major", "version_minor"])] {
+            for kind in kinds {
+                label_names.insert(*kind, labels.iter().map(|label| label.to_string()).collect());
+            }
+        }
+        let or_default = |buckets: &[f64], default: &[f64]| {
+            if buckets.is_empty() { default.to_vec() } else { buckets.to_vec() }
+        };
+        let long_duration_buckets =
+            or_default(&config.long_duration_buckets, &LONG_DURATION_BUCKETS);
+        let short_duration_buckets =
+            or_default(&config.short_duration_buckets, &SHORT_DURATION_BUCKETS);
+        let transfer_rate_buckets =
+            or_default(&config.transfer_rate_buckets, &TRANSFER_RATE_BUCKETS);
+        let enabled = |name: &str| {
+            debug_assert!(METRIC_FAMILIES.contains(&name));
+            let kind = name.split('_').next().unwrap();
+            !config.disabled_metrics.iter().any(|d| d == name || d == kind)
+        };
+        let int_counter = |name: &str, help: &str| {
+            registered(registry, name, IntCounter::new(metric_names.get(name), help))
+        };
+        let int_counter_vec = |name: &str, help: &str, labels: &[&str]| {
+            registered(registry, name,
+                       IntCounterVec::new(Opts::new(metric_names.get(name), help), labels))
+        };
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            if !enabled(name) {
+                return Ok(None);
+            }
+            int_counter_vec(name, help, labels).map(Some)
+        };
+        let seconds_counter = |name: &str, help: &str, labels: &[&str]| {
+            if !enabled(name) {
+                return Ok(None);
+            }
+            registered(registry, name,
+                       CounterVec::new(Opts::new(metric_names.get(name), help), labels))
+                .map(Some)
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str], buckets: &[f64]| {
+            if !enabled(name) {
+                return Ok(None);
+            }
+            let opts = HistogramOpts::new(metric_names.get(name), help)
+                .buckets(Vec::from(buckets));
+            let vec = registered(registry, name, HistogramVec::new(opts, labels))?;
+            let exemplars = config.enable_exemplars.then(|| {
+                exemplar::register(&metric_names.get(name), labels, buckets)
+            });
+            Ok(Some(Distribution::Histogram(vec, exemplars)))
+        };
+        let duration = |name: &str, help: &str, labels: &[&str], buckets: &[f64]| {
+            match config.metric_style {
+                MetricStyle::Histogram => { histogram(name, help, labels, buckets) }
+                MetricStyle::Summary => {
+                    if !enabled(name) {
+                        return Ok(None);
+                    }
+                    let opts = SummaryOpts::new(
+                        metric_names.get(name), help,
+                        config.summary_objectives.clone());
+                    let vec = registered(registry, name, new_summary_vec(opts, labels))?;
+                    Ok(Some(Distribution::Summary(vec)))
+                }
+            }
+        };
+        let rate_gauge = |name: &str, help: &str, labels: &[&str]| {
+            if !config.enable_rate_gauges {
+                return Ok(None);
+            }
+            let opts = Opts::new(metric_names.get(name), help);
+            let gauges = registered(registry, name, GaugeVec::new(opts, labels))?;
+            Ok(Some(RateGaugeVec::new(gauges)))
+        };
+        let size_histogram = |kind: &str, labels: &[&str]| {
+            if !config.enable_size_histogram {
+                return Ok(None);
+            }
+            histogram(
+                &(kind.to_string() + "_file_size_bytes"),
+                &format!("A histogram of file sizes of {} events.", kind),
+                labels,
+                &FILE_SIZE_BUCKETS)
+        };
+        Ok(Collector {
+            remove_count: counter(
+                "remove_count",
                 "The number of remove events seen.",
-                REMOVE_REQUEST_LABELS).unwrap(),
-            remove_bytes: register_int_counter_vec!(
-                metric_prefix.clone() + "remove_bytes",
+                &remove_labels)?,
+            remove_bytes: counter(
+                "remove_bytes",
                 "The accumulated size of removed files.",
-                REMOVE_REQUEST_LABELS).unwrap(),
+                &remove_labels)?,
+            remove_storage_class_bytes: counter(
+                "remove_storage_class_bytes",
+                "The number of bytes removed per storage group and class.",
+                &["storage_group", "storage_class"])?,
+            remove_queuing_seconds: duration(
+                "remove_queuing_seconds",
+                "A histogram of queuing times of remove events.",
+                &remove_labels,
+                &long_duration_buckets)?,
+            remove_file_size_bytes: size_histogram("remove", &remove_labels)?,
 
-            request_count: register_int_counter_vec!(
-                metric_prefix.clone() + "request_count",
+            request_count: counter(
+                "request_count",
                 "The number of request events seen.",
-                REMOVE_REQUEST_LABELS).unwrap(),
-            request_session_seconds: register_histogram_vec!(
-                metric_prefix.clone() + "request_session_duration",
+                &request_labels)?,
+            request_subject_type_count: if config.enable_subject_type_metric {
+                counter(
+                    "request_subject_type_count",
+                    "The number of requests whose subject has a principal of each type.",
+                    &["type"])?
+            } else {
+                None
+            },
+            request_without_mover_count: counter(
+                "request_without_mover_count",
+                "The number of requests which were not served by a mover.",
+                &request_labels)?,
+            request_read_bytes: counter(
+                "request_read_bytes",
+                "The number of bytes read by the movers of requests.",
+                &request_labels)?,
+            request_write_bytes: counter(
+                "request_write_bytes",
+                "The number of bytes written by the movers of requests.",
+                &request_labels)?,
+            request_session_seconds: duration(
+                "request_session_seconds",
                 "A histogram of duration of request sessions.",
-                REMOVE_REQUEST_LABELS,
-                Vec::from(SHORT_DURATION_BUCKETS)).unwrap(),
+                &request_labels,
+                &short_duration_buckets)?,
+            request_queuing_seconds: duration(
+                "request_queuing_seconds",
+                "A histogram of queuing times of requests.",
+                &request_labels,
+                &long_duration_buckets)?,
+            request_file_size_bytes: size_histogram("request", &request_labels)?,
+            request_client_chain_depth: histogram(
+                "request_client_chain_depth",
+                "A histogram of the number of hosts in the client chain of requests.",
+                &request_labels,
+                &CLIENT_CHAIN_DEPTH_BUCKETS)?,
 
-            restore_count: register_int_counter_vec!(
-                metric_prefix.clone() + "restore_count",
+            restore_count: counter(
+                "restore_count",
                 "The number of restore events seen.",
-                RESTORE_STORE_LABELS).unwrap(),
-            restore_bytes: register_int_counter_vec!(
-                metric_prefix.clone() + "restore_bytes",
+                &restore_store_labels)?,
+            restore_bytes: counter(
+                "restore_bytes",
                 "The accumulated size of files attempted restored from tape.",
-                RESTORE_STORE_LABELS).unwrap(),
-            restore_seconds: register_histogram_vec!(
-                metric_prefix.clone() + "restore_seconds",
+                &restore_store_labels)?,
+            restore_success_bytes: counter(
+                "restore_success_bytes",
+                "The accumulated size of files successfully restored from tape.",
+                &restore_store_labels)?,
+            restore_seconds: duration(
+                "restore_seconds",
                 "A histogram of restore times.",
-                RESTORE_STORE_LABELS,
-                Vec::from(LONG_DURATION_BUCKETS)).unwrap(),
+                &restore_store_labels,
+                &long_duration_buckets)?,
+            restore_queuing_seconds: duration(
+                "restore_queuing_seconds",
+                "A histogram of queuing times of restores.",
+                &restore_store_labels,
+                &long_duration_buckets)?,
+            restore_file_size_bytes: size_histogram("restore", &restore_store_labels)?,
+            restore_location_count: counter(
+                "restore_location_count",
+                "The accumulated number of HSM locations of restored files.",
+                &restore_store_labels)?,
+            restore_failed_count: counter(
+                "restore_failed_count",
+                "The number of failed restores by simplified error message.",
+                &restore_store_failure_labels)?,
 
-            store_count: register_int_counter_vec!(
-                metric_prefix.clone() + "store_count",
+            store_count: counter(
+                "store_count",
                 "The number of store events seen.",
-                RESTORE_STORE_LABELS).unwrap(),
-            store_bytes: register_int_counter_vec!(
-                metric_prefix.clone() + "store_bytes",
+                &restore_store_labels)?,
+            store_bytes: counter(
+                "store_bytes",
                 "The accumulated size of files attempted flushed to tape.",
-                RESTORE_STORE_LABELS).unwrap(),
-            store_seconds: register_histogram_vec!(
-                metric_prefix.clone() + "store_seconds",
+                &restore_store_labels)?,
+            store_success_bytes: counter(
+                "store_success_bytes",
+                "The accumulated size of files successfully flushed to tape.",
+                &restore_store_labels)?,
+            store_seconds: duration(
+                "store_seconds",
                 "A histogram of store times.",
-                RESTORE_STORE_LABELS,
-                Vec::from(LONG_DURATION_BUCKETS)).unwrap(),
+                &restore_store_labels,
+                &long_duration_buckets)?,
+            store_queuing_seconds: duration(
+                "store_queuing_seconds",
+                "A histogram of queuing times of stores.",
+                &restore_store_labels,
+                &long_duration_buckets)?,
+            store_file_size_bytes: size_histogram("store", &restore_store_labels)?,
+            store_location_count: counter(
+                "store_location_count",
+                "The accumulated number of HSM locations of stored files.",
+                &restore_store_labels)?,
+            store_failed_count: counter(
+                "store_failed_count",
+                "The number of failed stores by simplified error message.",
+                &restore_store_failure_labels)?,
+            message_rewrite_rules: RwLock::new(message_rewrite_rules),
+            message_rewrite_rule_count,
 
-            transfer_count: register_int_counter_vec!(
-                metric_prefix.clone() + "transfer_count",
+            transfer_count: counter(
+                "transfer_count",
                 "The number of transfer events seen.",
-                TRANSFER_LABELS).unwrap(),
-            transfer_bytes: register_int_counter_vec!(
-                metric_prefix.clone() + "transfer_bytes",
+                &transfer_labels)?,
+            transfer_bytes: counter(
+                "transfer_bytes",
                 "The number of bytes transferred, including from failed transfers.",
-                TRANSFER_LABELS).unwrap(),
-            transfer_seconds: register_histogram_vec!(
-                metric_prefix.clone() + "transfer_seconds",
+                &transfer_labels)?,
+            transfer_seconds: duration(
+                "transfer_seconds",
                 "A histogram of transfer times.",
-                TRANSFER_LABELS,
-                Vec::from(LONG_DURATION_BUCKETS)).unwrap(),
-            transfer_mean_read_bandwidth_bytes_per_second: register_histogram_vec!(
-                metric_prefix.clone() + "transfer_mean_read_bandwidth_bytes_per_second",
+                &transfer_labels,
+                &long_duration_buckets)?,
+            transfer_p2p_bytes: counter(
+                "transfer_p2p_bytes",
+                "The number of bytes transferred between pools.",
+                TRANSFER_P2P_LABELS)?,
+            transfer_p2p_seconds: duration(
+                "transfer_p2p_seconds",
+                "A histogram of transfer times between pools.",
+                TRANSFER_P2P_LABELS,
+                &long_duration_buckets)?,
+            transfer_queuing_seconds: duration(
+                "transfer_queuing_seconds",
+                "A histogram of queuing times of transfers.",
+                &transfer_labels,
+                &long_duration_buckets)?,
+            transfer_file_size_bytes: size_histogram("transfer", &transfer_labels)?,
+            transfer_mean_read_bandwidth_bytes_per_second: histogram(
+                "transfer_mean_read_bandwidth_bytes_per_second",
                 "A histogram of the mean read bandwidth for transfers.",
-                TRANSFER_LABELS,
-                Vec::from(TRANSFER_RATE_BUCKETS)).unwrap(),
-            transfer_mean_write_bandwidth_bytes_per_second: register_histogram_vec!(
-                metric_prefix.clone() + "transfer_mean_write_bandwidth_bytes_per_second",
+                &transfer_labels,
+                &transfer_rate_buckets)?,
+            transfer_mean_write_bandwidth_bytes_per_second: histogram(
+                "transfer_mean_write_bandwidth_bytes_per_second",
                 "A histogram of the mean write bandwidth for transfers.",
-                TRANSFER_LABELS,
-                Vec::from(TRANSFER_RATE_BUCKETS)).unwrap(),
+                &transfer_labels,
+                &transfer_rate_buckets)?,
+            transfer_active_seconds: duration(
+                "transfer_active_seconds",
+                "A histogram of the time transfers spent reading or writing.",
+                &transfer_labels,
+                &long_duration_buckets)?,
+            transfer_idle_seconds: duration(
+                "transfer_idle_seconds",
+                "A histogram of the time transfers spent idle.",
+                &transfer_labels,
+                &long_duration_buckets)?,
+            transfer_version_count: if config.enable_version_metric {
+                counter(
+                    "transfer_version_count",
+                    "The number of transfers by protocol and protocol version.",
+                    &["protocol", "version_major", "version_minor"])?
+            } else {
+                None
+            },
+            transfer_protocol_count: counter(
+                "transfer_protocol_count",
+                "The number of transfers by protocol and major protocol version.",
+                &["protocol", "version_major"])?,
+            transfer_total_queuing_seconds: seconds_counter(
+                "transfer_total_queuing_seconds",
+                "The accumulated queuing time of transfers.",
+                &transfer_labels)?,
+            transfer_total_active_seconds: seconds_counter(
+                "transfer_total_active_seconds",
+                "The accumulated time transfers spent reading or writing.",
+                &transfer_labels)?,
+            transfer_total_idle_seconds: seconds_counter(
+                "transfer_total_idle_seconds",
+                "The accumulated time transfers spent idle.",
+                &transfer_labels)?,
+            total_read_bytes: int_counter(
+                "total_read_bytes",
+                "The number of bytes read by clients, excluding pool-to-pool transfers.")?,
+            total_write_bytes: int_counter(
+                "total_write_bytes",
+                "The number of bytes written by clients, excluding pool-to-pool transfers.")?,
+            transfer_read_count: int_counter(
+                "transfer_read_count",
+                "The number of reads by clients, excluding pool-to-pool transfers.")?,
+            transfer_write_count: int_counter(
+                "transfer_write_count",
+                "The number of writes by clients, excluding pool-to-pool transfers.")?,
+            transfer_p2p_count: int_counter(
+                "transfer_p2p_count",
+                "The number of pool-to-pool transfers.")?,
 
-            unparsed_count: register_int_counter!(
-                metric_prefix.clone() + "unparsed_count",
-                "The number of unparsed events.").unwrap(),
+            processed_count: int_counter_vec(
+                "processed_count",
+                "The number of events parsed and accounted by message type.",
+                &["msg_type"])?,
+            unparsed_count: int_counter_vec(
+                "unparsed_count",
+                "The number of unparsed events by the reason they failed to parse.",
+                &["reason"])?,
+            label_truncated_count: int_counter_vec(
+                "label_truncated_count",
+                "The number of label values truncated for exceeding the maximum length.",
+                &["label"])?,
+            missing_msgtype_count: int_counter(
+                "missing_msgtype_count",
+                "The number of events lacking a msgType.")?,
+            batch_count: int_counter(
+                "batch_count",
+                "The number of messages holding a JSON array of events.")?,
+            duplicate_count: int_counter(
+                "duplicate_count",
+                "The number of events skipped due to a recently seen transaction ID.")?,
+            recent_transactions:
+                (config.dedup_window > 0).then(|| RecentIds::new(config.dedup_window)),
+            tape_retry_count: if config.dedup_window > 0 {
+                Some(int_counter_vec(
+                    "tape_retry_count",
+                    "The number of restore and store records skipped for repeating a \
+                     recently seen transaction, as when dCache retries.",
+                    &["kind"])?)
+            } else {
+                None
+            },
+            label_last_seen: config.label_ttl.map(|_| Mutex::new(HashMap::new())),
+            label_ttl: config.label_ttl.unwrap_or_default(),
+            max_recent_queuing_seconds: if config.enable_max_recent_queuing {
+                Some(Mutex::new(registered(
+                    registry, "max_recent_queuing_seconds",
+                    GaugeVec::new(
+                        Opts::new(
+                            metric_names.get("max_recent_queuing_seconds"),
+                            "The maximum queuing time seen per cell since the last reset."),
+                        &["cell_name", "cell_domain"]))?))
+            } else {
+                None
+            },
+            unique_pnfsids: if config.enable_unique_pnfsid_estimate {
+                Some((Mutex::new(HyperLogLog::default()), registered(
+                    registry, "unique_pnfsid_estimate",
+                    Gauge::new(
+                        metric_names.get("unique_pnfsid_estimate"),
+                        "An estimate of the number of distinct PNFS IDs seen \
+                         since the start of the window."))?))
+            } else {
+                None
+            },
+            transfer_bytes_rate: rate_gauge(
+                "transfer_bytes_rate",
+                "A moving average of the bytes transferred per second.",
+                &["direction"])?,
+            restore_bytes_rate: rate_gauge(
+                "restore_bytes_rate",
+                "A moving average of the bytes restored per second.",
+                &[])?,
+            store_bytes_rate: rate_gauge(
+                "store_bytes_rate",
+                "A moving average of the bytes stored per second.",
+                &[])?,
+            known_series: config.max_series.map(|max| (max, Mutex::new(HashSet::new()))),
+            series_overflow_count: int_counter(
+                "series_overflow_count",
+                "The number of updates of metrics under the overflow label combination.")?,
+            ignored_count: int_counter_vec(
+                "ignored_count",
+                "The number of events of known but unhandled message types.",
+                &["msg_type"])?,
+            missing_storage_info_count: int_counter_vec(
+                "missing_storage_info_count",
+                "The number of events lacking storage info.",
+                &["msg_type"])?,
+            transfer_initiator_count: int_counter_vec(
+                "transfer_initiator_count",
+                "The number of transfers by what initiated them, being client, pool, \
+                 system, or unknown.",
+                &["type"])?,
+            ignored_msg_types: config.ignored_msg_types,
+            include_cell_domains: config.include_cell_domains,
+            exclude_cell_domains: config.exclude_cell_domains,
+            cell_name_rewrites: config.cell_name_rewrites,
+            filtered_count: int_counter(
+                "filtered_count",
+                "The number of events skipped due to the cell domain filters.")?,
+            message_compression: config.message_compression,
+            max_message_bytes: config.max_message_bytes,
+            oversize_dropped_count: int_counter(
+                "oversize_dropped_count",
+                "The number of messages dropped for exceeding --max-message-bytes, \
+                 before or after decompression.")?,
+            oversize_warning_throttle: LogThrottle::new(
+                "oversize warnings", config.log_warn_rate, Duration::from_secs(60)),
+            tombstone_count: int_counter(
+                "tombstone_count",
+                "The number of messages without a value, which were skipped.")?,
+            consumed_count: int_counter_vec(
+                "consumed_count",
+                "The number of messages consumed from each partition.",
+                &["topic", "partition"])?,
+            current_offset: registered(
+                registry, "current_offset",
+                IntGaugeVec::new(
+                    Opts::new(
+                        metric_names.get("current_offset"),
+                        "The offset of the last message consumed from each partition."),
+                    &["topic", "partition"]))?,
+            message_bytes: registered(
+                registry, "message_bytes",
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        metric_names.get("message_bytes"),
+                        "A histogram of the sizes of consumed messages, whether or not \
+                         they parse.")
+                    .buckets(Vec::from(MESSAGE_SIZE_BUCKETS))))?,
+            duration_unit: config.duration_unit,
+            deadletter: config.deadletter,
+            debug_messages: config.debug_messages,
+            anonymize_client_ip: config.anonymize_client_ip,
+            status_categories: config.status_categories,
+            cell_categories: config.cell_categories,
+            hsm_error_classes: config.hsm_error_classes,
+            enable_vo_label: config.enable_vo_label,
+            enable_pool_label: config.enable_pool_label,
+            enable_initiator_label: config.enable_initiator_label,
+            enable_key_label: config.key_label.is_some(),
+            kept_labels,
+            label_names,
+            max_label_length: config.max_label_length,
+            histogram_sample_rate: config.histogram_sample_rate,
+            last_message_timestamp_seconds: registered(
+                registry, "last_message_timestamp_seconds",
+                Gauge::new(
+                    metric_names.get("last_message_timestamp_seconds"),
+                    "The Unix time when the last event was successfully processed."))?,
+            process_duration_seconds: registered(
+                registry, "process_duration_seconds",
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        metric_names.get("process_duration_seconds"),
+                        "A histogram of the time spent parsing and accounting each message.")
+                    .buckets(Vec::from(PROCESSING_DURATION_BUCKETS))))?,
+            parse_warning_throttle: LogThrottle::new(
+                "parse warnings", config.log_warn_rate, Duration::from_secs(60)),
+            label_truncation_warning_throttle: LogThrottle::new(
+                "label truncation warnings", config.log_warn_rate, Duration::from_secs(60)),
+        })
+    }
+
+    // Converts a duration from a record to seconds.
+    fn seconds(&self, duration: u64) -> f64 {
+        duration as f64 / self.duration_unit.per_second()
+    }
+
+    // The cell_name label value.
+    fn cell_name_label<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match self.cell_name_rewrites.iter().find(|(re, _)| re.is_match(name)) {
+            Some((re, replacement)) => { re.replace(name, replacement.as_str()) }
+            None => { name.into() }
         }
     }
 
-    fn update_metrics(&mut self, msg: Message) {
+    // Label values corresponding to the above labels, followed by the values
+    // of enabled optional labels.
+    fn label_values<'a>(&'a self, msg: &'a Message, origin: &'a Origin)
+        -> Vec<Cow<'a, str>>
+    {
+        let mut values: Vec<Cow<str>> = match msg {
+            Message::Remove {cell, status, storage_info, ..} |
+            Message::Request {cell, status, storage_info, ..} => {
+                let storage_info: &str = match storage_info {
+                    None => { "" }
+                    Some(s) => { s.as_str() }
+                };
+                vec![
+                    self.cell_name_label(&cell.name), cell.domain.as_str().into(),
+                    cell.type_.as_str().into(), self.cell_categories.category(&cell.type_).into(),
+                    status_code_label(status.code),
+                    storage_info.into(),
+                ]
+            }
+            Message::Restore {cell, status, storage_info, hsm, ..} |
+            Message::Store {cell, status, storage_info, hsm, ..} => {
+                vec![
+                    self.cell_name_label(&cell.name), cell.domain.as_str().into(),
+                    cell.type_.as_str().into(), self.cell_categories.category(&cell.type_).into(),
+                    status_code_label(status.code),
+                    storage_info.as_str().into(),
+                    hsm.instance.as_str().into(), hsm.provider.as_str().into(),
+                    hsm.type_.as_str().into(),
+                ]
+            }
+            Message::Transfer {cell, direction, storage_info, ..} => {
+                vec![
+                    self.cell_name_label(&cell.name), cell.domain.as_str().into(),
+                    cell.type_.as_str().into(), self.cell_categories.category(&cell.type_).into(),
+                    direction.to_string().into(),
+                    storage_info.as_str().into(),
+                ]
+            }
+        };
+        if let Some(status_categories) = &self.status_categories {
+            if let Message::Remove {status, ..} | Message::Request {status, ..} |
+                   Message::Restore {status, ..} | Message::Store {status, ..} = msg {
+                values.push(status_categories.category(status.code).into());
+            }
+        }
+        if self.enable_vo_label {
+            if let Message::Request {subject, ..} | Message::Transfer {subject, ..} = msg {
+                values.push(primary_vo(subject).unwrap_or("unknown").into());
+            }
+        }
+        if self.enable_pool_label {
+            if let Message::Transfer {cell, local_endpoint, ..} = msg {
+                let pool = match local_endpoint {
+                    Some(endpoint) => { endpoint_host(endpoint) }
+                    None => { cell.name.as_str() }
+                };
+                values.push(pool.into());
+            }
+        }
+        if self.enable_initiator_label {
+            if let Message::Transfer {initiator, ..} = msg {
+                values.push(initiator_cell(initiator).unwrap_or("unknown").into());
+            }
+        }
+        if self.enable_key_label {
+            values.push(origin.key.as_str().into());
+        }
+        if let Some(kept) = self.kept_labels.get(Self::kind(msg)) {
+            let mut kept = kept.iter();
+            values.retain(|_| *kept.next().unwrap());
+        }
+        values
+    }
+
+    // Prepares the label values of a kind for use: truncates those exceeding
+    // the maximum length, folds them into the overflow combination if they
+    // would exceed the series limit, and records their use for expiry.
+    fn prepare_labels(&self, kind: &'static str, values: &mut [Cow<str>]) {
+        self.truncate_label_values(kind, values);
+        self.fold_overflow(kind, values);
+        self.mark_seen(kind, values);
+    }
+
+    // Truncates the label values exceeding the maximum length.
+    fn truncate_label_values(&self, kind: &'static str, values: &mut [Cow<str>]) {
+        if self.max_label_length == 0 {
+            return;
+        }
+        for (value, name) in values.iter_mut().zip(&self.label_names[kind]) {
+            let Some(truncated) = truncate_label(value, self.max_label_length) else {
+                continue;
+            };
+            if self.label_truncation_warning_throttle.allow() {
+                warn!(label = name.as_str(), length = value.chars().count();
+                      "Truncating an overlong label value.");
+            }
+            self.label_truncated_count.with_label_values(&[name]).inc();
+            *value = truncated.into();
+        }
+    }
+
+    fn kind(msg: &Message) -> &'static str {
         match msg {
-            Message::Remove {file_size, ..} => {
-                proj(&self.remove_count, &msg).inc();
-                proj(&self.remove_bytes, &msg).inc_by(file_size);
-            }
-            Message::Request {session_duration, ..} => {
-                proj(&self.request_count, &msg).inc();
-                proj(&self.request_session_seconds, &msg).observe(session_duration as f64 / 1000.0);
-            }
-            Message::Restore {file_size, transfer_time, ..} => {
-                proj(&self.restore_count, &msg).inc();
-                proj(&self.restore_bytes, &msg).inc_by(file_size);
-                proj(&self.restore_seconds, &msg).observe(transfer_time as f64 / 1000.0);
-            }
-            Message::Store {file_size, transfer_time, ..} => {
-                proj(&self.store_count, &msg).inc();
-                proj(&self.store_bytes, &msg).inc_by(file_size);
-                proj(&self.store_seconds, &msg).observe(transfer_time as f64 / 1000.0);
-            }
-            Message::Transfer {transfer_size, transfer_time,
-                               mean_read_bandwidth, mean_write_bandwidth, ..} => {
-                proj(&self.transfer_count, &msg).inc();
-                proj(&self.transfer_bytes, &msg).inc_by(transfer_size);
-                proj(&self.transfer_seconds, &msg).observe(transfer_time as f64 / 1000.0);
+            Message::Remove {..} => { "remove" }
+            Message::Request {..} => { "request" }
+            Message::Restore {..} => { "restore" }
+            Message::Store {..} => { "store" }
+            Message::Transfer {..} => { "transfer" }
+        }
+    }
+
+    // The metrics labelled by the label values of the given kind of event,
+    // or of pool-to-pool transfers or failures.
+    fn metrics_of_kind(&self, kind: &str)
+        -> (Vec<&Option<IntCounterVec>>, Vec<&Option<Distribution>>)
+    {
+        match kind {
+            "remove" => {
+                (vec![&self.remove_count, &self.remove_bytes],
+                 vec![&self.remove_queuing_seconds, &self.remove_file_size_bytes])
+            }
+            "request" => {
+                (vec![&self.request_count, &self.request_without_mover_count,
+                      &self.request_read_bytes, &self.request_write_bytes],
+                 vec![&self.request_session_seconds, &self.request_queuing_seconds,
+                      &self.request_file_size_bytes, &self.request_client_chain_depth])
+            }
+            "restore" => {
+                (vec![&self.restore_count, &self.restore_bytes, &self.restore_success_bytes,
+                      &self.restore_location_count],
+                 vec![&self.restore_seconds, &self.restore_queuing_seconds,
+                      &self.restore_file_size_bytes])
+            }
+            "store" => {
+                (vec![&self.store_count, &self.store_bytes, &self.store_success_bytes,
+                      &self.store_location_count],
+                 vec![&self.store_seconds, &self.store_queuing_seconds,
+                      &self.store_file_size_bytes])
+            }
+            "transfer" => {
+                (vec![&self.transfer_count, &self.transfer_bytes],
+                 vec![&self.transfer_seconds,
+                      &self.transfer_queuing_seconds,
+                      &self.transfer_file_size_bytes,
+                      &self.transfer_mean_read_bandwidth_bytes_per_second,
+                      &self.transfer_mean_write_bandwidth_bytes_per_second,
+                      &self.transfer_active_seconds, &self.transfer_idle_seconds])
+            }
+            "transfer_p2p" => {
+                (vec![&self.transfer_p2p_bytes], vec![&self.transfer_p2p_seconds])
+            }
+            "remove_storage_class" => { (vec![&self.remove_storage_class_bytes], vec![]) }
+            "transfer_protocol" => { (vec![&self.transfer_protocol_count], vec![]) }
+            "transfer_version" => { (vec![&self.transfer_version_count], vec![]) }
+            // The failure counts add labels to those of the events.
+            "restore_failed" => { (vec![&self.restore_failed_count], vec![]) }
+            "store_failed" => { (vec![&self.store_failed_count], vec![]) }
+            _ => { (vec![], vec![]) }
+        }
+    }
+
+    // The counters of seconds labelled by the label values of the given kind
+    // of event.
+    fn seconds_counters_of_kind(&self, kind: &str) -> Vec<&Option<CounterVec>> {
+        match kind {
+            "transfer" => {
+                vec![&self.transfer_total_queuing_seconds, &self.transfer_total_active_seconds,
+                     &self.transfer_total_idle_seconds]
+            }
+            _ => { vec![] }
+        }
+    }
+
+    // Describes the enabled metrics.
+    pub fn schema(&self) -> Vec<MetricSchema> {
+        let mut schema = vec![];
+        for kind in ["remove", "request", "restore", "store", "transfer", "transfer_p2p"] {
+            let (counters, distributions) = self.metrics_of_kind(kind);
+            for counter in counters.into_iter().flatten() {
+                schema.extend(describe(counter, "counter"));
+            }
+            for counter in self.seconds_counters_of_kind(kind).into_iter().flatten() {
+                schema.extend(describe(counter, "counter"));
+            }
+            for distribution in distributions.into_iter().flatten() {
+                match distribution {
+                    Distribution::Histogram(vec, _) => {
+                        schema.extend(describe(vec, "histogram"));
+                    }
+                    Distribution::Summary(vec) => {
+                        schema.extend(describe(vec, "summary"));
+                    }
+                }
+            }
+        }
+        for counter in [&self.remove_storage_class_bytes, &self.request_subject_type_count,
+                        &self.restore_failed_count, &self.store_failed_count,
+                        &self.tape_retry_count,
+                        &self.transfer_version_count, &self.transfer_protocol_count]
+                       .into_iter().flatten() {
+            schema.extend(describe(counter, "counter"));
+        }
+        for counter in [&self.total_read_bytes, &self.total_write_bytes,
+                        &self.transfer_read_count, &self.transfer_write_count,
+                        &self.transfer_p2p_count, &self.missing_msgtype_count,
+                        &self.duplicate_count, &self.batch_count,
+                        &self.filtered_count, &self.series_overflow_count,
+                        &self.oversize_dropped_count, &self.tombstone_count] {
+            schema.extend(describe(counter, "counter"));
+        }
+        schema.extend(describe(&self.consumed_count, "counter"));
+        schema.extend(describe(&self.current_offset, "gauge"));
+        schema.extend(describe(&self.message_bytes, "histogram"));
+        schema.extend(describe(&self.processed_count, "counter"));
+        schema.extend(describe(&self.unparsed_count, "counter"));
+        schema.extend(describe(&self.label_truncated_count, "counter"));
+        schema.extend(describe(&self.ignored_count, "counter"));
+        schema.extend(describe(&self.missing_storage_info_count, "counter"));
+        schema.extend(describe(&self.transfer_initiator_count, "counter"));
+        schema.extend(describe(&self.last_message_timestamp_seconds, "gauge"));
+        if let Some(max_recent_queuing_seconds) = &self.max_recent_queuing_seconds {
+            let max_recent_queuing_seconds =
+                max_recent_queuing_seconds.lock().unwrap_or_else(PoisonError::into_inner);
+            schema.extend(describe(&*max_recent_queuing_seconds, "gauge"));
+        }
+        if let Some((_, gauge)) = &self.unique_pnfsids {
+            schema.extend(describe(gauge, "gauge"));
+        }
+        for rate in [&self.transfer_bytes_rate, &self.restore_bytes_rate,
+                     &self.store_bytes_rate].into_iter().flatten() {
+            schema.extend(describe(rate.gauges(), "gauge"));
+        }
+        schema.extend(describe(&self.message_rewrite_rule_count, "gauge"));
+        schema.extend(describe(&self.process_duration_seconds, "histogram"));
+        schema
+    }
+
+    // Running totals for the periodic statistics log.
+    pub fn totals(&self) -> Totals {
+        let mut processed = BTreeMap::new();
+        for family in self.processed_count.collect() {
+            for metric in family.get_metric() {
+                let msg_type = metric.get_label()[0].get_value().to_string();
+                processed.insert(msg_type, metric.get_counter().get_value() as u64);
+            }
+        }
+        let unparsed = self.unparsed_count.collect().iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value() as u64)
+            .sum::<u64>() + self.missing_msgtype_count.get();
+        Totals {
+            processed,
+            unparsed,
+            last_message_timestamp: self.last_message_timestamp_seconds.get(),
+        }
+    }
+
+    // The counters to preserve across restarts.
+    pub fn counters(&self) -> Vec<&dyn state::Counter> {
+        let mut counters: Vec<&dyn state::Counter> = vec![];
+        for kind in ["remove", "request", "restore", "store", "transfer", "transfer_p2p"] {
+            for counter in self.metrics_of_kind(kind).0.into_iter().flatten() {
+                counters.push(counter);
+            }
+            for counter in self.seconds_counters_of_kind(kind).into_iter().flatten() {
+                counters.push(counter);
+            }
+        }
+        for counter in [&self.remove_storage_class_bytes, &self.request_subject_type_count,
+                        &self.restore_failed_count, &self.store_failed_count,
+                        &self.tape_retry_count,
+                        &self.transfer_version_count, &self.transfer_protocol_count]
+                       .into_iter().flatten() {
+            counters.push(counter);
+        }
+        for counter in [&self.total_read_bytes, &self.total_write_bytes,
+                        &self.transfer_read_count, &self.transfer_write_count,
+                        &self.transfer_p2p_count, &self.missing_msgtype_count,
+                        &self.duplicate_count, &self.batch_count,
+                        &self.filtered_count, &self.series_overflow_count] {
+            counters.push(counter);
+        }
+        counters.push(&self.processed_count);
+        counters.push(&self.unparsed_count);
+        counters.push(&self.label_truncated_count);
+        counters.push(&self.ignored_count);
+        counters.push(&self.missing_storage_info_count);
+        counters.push(&self.transfer_initiator_count);
+        counters.push(&self.consumed_count);
+        counters.push(&self.oversize_dropped_count);
+        counters.push(&self.tombstone_count);
+        counters
+    }
+
+    // Reports the log messages suppressed in past intervals.  This should be
+    // called every few seconds.
+    pub fn flush_log_throttles(&self) {
+        for throttle in [&self.parse_warning_throttle, &self.label_truncation_warning_throttle,
+                         &self.oversize_warning_throttle] {
+            throttle.flush();
+        }
+    }
+
+    // Folds recent events into the byte rates.  This should be called every
+    // rate::DECAY_INTERVAL.
+    pub fn decay_rates(&self) {
+        for rate in [&self.transfer_bytes_rate, &self.restore_bytes_rate,
+                     &self.store_bytes_rate].into_iter().flatten() {
+            rate.decay();
+        }
+    }
+
+    // Replaces the rules used to simplify error messages.
+    pub fn set_message_rewrite_rules(&self, rules: MessageRewriteRules) {
+        self.message_rewrite_rule_count.set(rules.len() as i64);
+        *self.message_rewrite_rules.write().unwrap_or_else(PoisonError::into_inner) = rules;
+    }
+
+    // Starts a new window for the maximum queuing times.
+    pub fn reset_max_recent_queuing(&self) {
+        if let Some(max_recent_queuing_seconds) = &self.max_recent_queuing_seconds {
+            max_recent_queuing_seconds.lock().unwrap_or_else(PoisonError::into_inner).reset();
+            if let Some((_, known_series)) = &self.known_series {
+                known_series.lock().unwrap_or_else(PoisonError::into_inner)
+                    .retain(|(kind, _)| *kind != "max_recent_queuing");
+            }
+        }
+    }
+
+    // Updates the estimate of distinct PNFS IDs, and starts a new window if
+    // `reset` is true.
+    pub fn update_unique_pnfsids(&self, reset: bool) {
+        if let Some((pnfsids, gauge)) = &self.unique_pnfsids {
+            let mut pnfsids = pnfsids.lock().unwrap_or_else(PoisonError::into_inner);
+            gauge.set(pnfsids.estimate().round());
+            if reset {
+                pnfsids.clear();
+            }
+        }
+    }
+
+    fn update_max_recent_queuing(&self, msg: &Message) {
+        let (Some(max_recent_queuing_seconds), Some(queuing_time)) =
+            (&self.max_recent_queuing_seconds, msg.queuing_time()) else {
+            return;
+        };
+        let cell = msg.cell();
+        let mut values = [self.cell_name_label(&cell.name), cell.domain.as_str().into()];
+        self.prepare_labels("max_recent_queuing", &mut values);
+        let labels = [values[0].as_ref(), values[1].as_ref()];
+        let queuing_seconds = self.seconds(queuing_time);
+        let max_recent_queuing_seconds =
+            max_recent_queuing_seconds.lock().unwrap_or_else(PoisonError::into_inner);
+        let gauge = max_recent_queuing_seconds.with_label_values(&labels);
+        if queuing_seconds > gauge.get() {
+            gauge.set(queuing_seconds);
+        }
+    }
+
+    // Removes the series of label combinations which have not been seen
+    // within the label TTL.
+    pub fn expire_labels(&self) {
+        let Some(label_last_seen) = &self.label_last_seen else {
+            return;
+        };
+        let mut expired = vec![];
+        label_last_seen.lock().unwrap_or_else(PoisonError::into_inner).retain(|key, last_seen| {
+            let keep = last_seen.elapsed() < self.label_ttl;
+            if !keep {
+                expired.push(key.clone());
+            }
+            keep
+        });
+        if let Some((_, known_series)) = &self.known_series {
+            let mut known_series = known_series.lock().unwrap_or_else(PoisonError::into_inner);
+            for key in &expired {
+                known_series.remove(key);
+            }
+        }
+        for (kind, values) in expired {
+            let labels: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+            let (counters, distributions) = self.metrics_of_kind(kind);
+            for counter in counters.into_iter().flatten() {
+                let _ = counter.remove_label_values(&labels);
+            }
+            for counter in self.seconds_counters_of_kind(kind).into_iter().flatten() {
+                let _ = counter.remove_label_values(&labels);
+            }
+            for distribution in distributions.into_iter().flatten() {
+                distribution.remove(&labels);
+            }
+        }
+    }
+
+    // Counts a failure labelled by the simplified error message, unless the
+    // status indicates success.
+    // The label combinations of the failure counts are tracked as those of
+    // the kinds "restore_failed" and "store_failed".
+    fn count_failure(&self, kind: &'static str, counter: &Option<IntCounterVec>,
+                     labels: &[&str], status: &Status) {
+        if status.code == 0 || counter.is_none() {
+            return;
+        }
+        let error = self.message_rewrite_rules.read().unwrap_or_else(PoisonError::into_inner)
+            .rewrite(&status.msg);
+        let mut values: Vec<Cow<str>> = labels.iter().map(|&label| label.into()).collect();
+        values.push(error.into());
+        if let Some(hsm_error_classes) = &self.hsm_error_classes {
+            values.push(hsm_error_classes.classify(&status.msg).into());
+        }
+        self.prepare_labels(kind, &mut values);
+        let labels: Vec<&str> = values.iter().map(|v| v.as_ref()).collect();
+        inc(counter, &labels);
+    }
+
+    // Adds to a counter under label values of a kind, which are prepared
+    // first.
+    fn inc_by_prepared(&self, kind: &'static str, counter: &Option<IntCounterVec>,
+                       mut values: Vec<Cow<str>>, v: u64) {
+        if counter.is_none() {
+            return;
+        }
+        self.prepare_labels(kind, &mut values);
+        let labels: Vec<&str> = values.iter().map(|v| v.as_ref()).collect();
+        inc_by(counter, &labels, v);
+    }
+
+    // Records when a label combination was last used, for expiry.
+    fn mark_seen(&self, kind: &'static str, values: &[impl AsRef<str>]) {
+        if let Some(label_last_seen) = &self.label_last_seen {
+            let key = (kind, values.iter().map(|v| v.as_ref().to_string()).collect());
+            label_last_seen.lock().unwrap_or_else(PoisonError::into_inner)
+                .insert(key, Instant::now());
+        }
+    }
+
+    // Whether a new label combination would exceed the series limit, in
+    // which case the overflow combination should be used instead.
+    fn is_overflow(&self, kind: &'static str, values: &[impl AsRef<str>]) -> bool {
+        let Some((max_series, known_series)) = &self.known_series else {
+            return false;
+        };
+        let key = (kind, values.iter().map(|v| v.as_ref().to_string()).collect());
+        let mut known_series = known_series.lock().unwrap_or_else(PoisonError::into_inner);
+        if known_series.contains(&key) {
+            return false;
+        }
+        if known_series.len() < *max_series {
+            known_series.insert(key);
+            return false;
+        }
+        true
+    }
+
+    // Replaces the label values by the overflow combination if they would
+    // exceed the series limit.
+    fn fold_overflow(&self, kind: &'static str, values: &mut [Cow<str>]) {
+        if self.is_overflow(kind, values) {
+            self.series_overflow_count.inc();
+            values.fill(Cow::Borrowed(OVERFLOW_LABEL_VALUE));
+        }
+    }
+
+    // Updates the metrics of a pool-to-pool transfer, which the source pool
+    // reports with the destination pool as the initiator.
+    fn update_p2p_metrics(&self, cell: &Cell, initiator: &str, transfer_size: u64,
+                          transfer_time: Option<u64>) {
+        let source_pool = self.cell_name_label(&cell.name);
+        let dest_pool = match initiator_type(initiator) {
+            "pool" => { initiator_cell(initiator).map(|cell| self.cell_name_label(cell)) }
+            _ => { None }
+        };
+        let dest_pool = dest_pool.unwrap_or(Cow::Borrowed("unknown"));
+        let mut values = [source_pool, dest_pool];
+        self.prepare_labels("transfer_p2p", &mut values);
+        let labels = [values[0].as_ref(), values[1].as_ref()];
+        inc_by(&self.transfer_p2p_bytes, &labels, transfer_size);
+        if let Some(transfer_time) = transfer_time {
+            observe(&self.transfer_p2p_seconds, &labels, self.seconds(transfer_time));
+        }
+    }
+
+    fn update_metrics(&self, msg: Message, origin: &Origin) {
+        self.processed_count.with_label_values(&[Self::kind(&msg)]).inc();
+        self.update_max_recent_queuing(&msg);
+        if let (Some((pnfsids, _)), Some(pnfsid)) = (&self.unique_pnfsids, msg.pnfsid()) {
+            pnfsids.lock().unwrap_or_else(PoisonError::into_inner).insert(pnfsid);
+        }
+        let mut values = self.label_values(&msg, origin);
+        self.prepare_labels(Self::kind(&msg), &mut values);
+        let labels: Vec<&str> = values.iter().map(|v| v.as_ref()).collect();
+        let labels = labels.as_slice();
+        let sample = self.histogram_sample_rate >= 1.0 ||
+            rand::thread_rng().gen_bool(self.histogram_sample_rate);
+        match msg {
+            Message::Remove {file_size, queuing_time, ref storage_info, ..} => {
+                if storage_info.is_none() {
+                    self.missing_storage_info_count.with_label_values(&["remove"]).inc();
+                }
+                inc(&self.remove_count, labels);
+                if let Some(file_size) = file_size {
+                    inc_by(&self.remove_bytes, labels, file_size);
+                    let (storage_group, storage_class) =
+                        storage_group_and_class(storage_info.as_deref());
+                    self.inc_by_prepared("remove_storage_class", &self.remove_storage_class_bytes,
+                                         vec![storage_group.into(), storage_class.into()],
+                                         file_size);
+                    observe(&self.remove_file_size_bytes, labels, file_size as f64);
+                }
+                if let Some(queuing_time) = queuing_time {
+                    observe(sampled(sample, &self.remove_queuing_seconds), labels,
+                            self.seconds(queuing_time));
+                }
+            }
+            Message::Request {session_duration, queuing_time, file_size, ref storage_info,
+                              ref client_chain, ref mover_info, ref pnfsid, ref subject, ..} => {
+                if storage_info.is_none() {
+                    self.missing_storage_info_count.with_label_values(&["request"]).inc();
+                }
+                inc(&self.request_count, labels);
+                if self.request_subject_type_count.is_some() {
+                    for type_ in principal_types(subject) {
+                        inc(&self.request_subject_type_count, &[type_]);
+                    }
+                }
+                // The pool also reports the transfer of the mover, so it is
+                // not added to the transfer metrics here.
+                match mover_info.as_deref() {
+                    None => { inc(&self.request_without_mover_count, labels) }
+                    Some(MoverInfo::Transfer {direction, transfer_size, ..}) => {
+                        // An unknown size is reported as -1.
+                        let transfer_size = &transfer_size.unwrap_or(0);
+                        match direction {
+                            Direction::Read => {
+                                inc_by(&self.request_read_bytes, labels, *transfer_size);
+                            }
+                            Direction::Write => {
+                                inc_by(&self.request_write_bytes, labels, *transfer_size);
+                            }
+                            Direction::P2p => {}
+                        }
+                    }
+                }
+                let observe_request = |distribution, v| {
+                    match pnfsid {
+                        Some(pnfsid) => { observe_with_pnfsid(distribution, labels, v, pnfsid) }
+                        None => { observe(distribution, labels, v) }
+                    }
+                };
+                if let Some(session_duration) = session_duration {
+                    observe_request(sampled(sample, &self.request_session_seconds),
+                                    self.seconds(session_duration));
+                }
+                if let Some(queuing_time) = queuing_time {
+                    observe_request(sampled(sample, &self.request_queuing_seconds),
+                                    self.seconds(queuing_time));
+                }
+                if let Some(file_size) = file_size {
+                    observe(&self.request_file_size_bytes, labels, file_size as f64);
+                }
+                observe(&self.request_client_chain_depth, labels,
+                        client_chain_depth(client_chain) as f64);
+            }
+            Message::Restore {file_size, transfer_time, queuing_time, ref pnfsid, ref locations,
+                              ref status, ..} => {
+                inc(&self.restore_count, labels);
+                self.count_failure("restore_failed", &self.restore_failed_count, labels, status);
+                if let Some(file_size) = file_size {
+                    inc_by(&self.restore_bytes, labels, file_size);
+                    add_rate(&self.restore_bytes_rate, &[], file_size);
+                    if status.code == 0 {
+                        inc_by(&self.restore_success_bytes, labels, file_size);
+                    }
+                    observe(&self.restore_file_size_bytes, labels, file_size as f64);
+                }
+                if let Some(transfer_time) = transfer_time {
+                    observe_with_pnfsid(sampled(sample, &self.restore_seconds), labels,
+                                        self.seconds(transfer_time), pnfsid);
+                }
+                if let Some(queuing_time) = queuing_time {
+                    observe_with_pnfsid(sampled(sample, &self.restore_queuing_seconds), labels,
+                                        self.seconds(queuing_time), pnfsid);
+                }
+                inc_by(&self.restore_location_count, labels, locations.len() as u64);
+            }
+            Message::Store {file_size, transfer_time, queuing_time, ref pnfsid, ref locations,
+                            ref status, ..} => {
+                inc(&self.store_count, labels);
+                self.count_failure("store_failed", &self.store_failed_count, labels, status);
+                if let Some(file_size) = file_size {
+                    inc_by(&self.store_bytes, labels, file_size);
+                    add_rate(&self.store_bytes_rate, &[], file_size);
+                    if status.code == 0 {
+                        inc_by(&self.store_success_bytes, labels, file_size);
+                    }
+                    observe(&self.store_file_size_bytes, labels, file_size as f64);
+                }
+                if let Some(transfer_time) = transfer_time {
+                    observe_with_pnfsid(sampled(sample, &self.store_seconds), labels,
+                                        self.seconds(transfer_time), pnfsid);
+                }
+                if let Some(queuing_time) = queuing_time {
+                    observe_with_pnfsid(sampled(sample, &self.store_queuing_seconds), labels,
+                                        self.seconds(queuing_time), pnfsid);
+                }
+                inc_by(&self.store_location_count, labels, locations.len() as u64);
+            }
+            Message::Transfer {transfer_size, transfer_time, queuing_time, ref direction,
+                               mean_read_bandwidth, mean_write_bandwidth,
+                               ref read_active, ref read_idle,
+                               ref write_active, ref write_idle, ref pnfsid,
+                               ref protocol_info, ref initiator, ref cell, ..} => {
+                inc(&self.transfer_count, labels);
+                self.transfer_initiator_count.with_label_values(&[initiator_type(initiator)]).inc();
+                inc_by(&self.transfer_bytes, labels, transfer_size);
+                add_rate(&self.transfer_bytes_rate, &[&direction.to_string()], transfer_size);
+                match direction {
+                    Direction::Read => {
+                        self.transfer_read_count.inc();
+                        self.total_read_bytes.inc_by(transfer_size);
+                    }
+                    Direction::Write => {
+                        self.transfer_write_count.inc();
+                        self.total_write_bytes.inc_by(transfer_size);
+                    }
+                    Direction::P2p => {
+                        self.transfer_p2p_count.inc();
+                        self.update_p2p_metrics(cell, initiator, transfer_size,
+                                                transfer_time.filter(|_| sample));
+                    }
+                }
+                if let Some(transfer_time) = transfer_time {
+                    observe_with_pnfsid(sampled(sample, &self.transfer_seconds), labels,
+                                        self.seconds(transfer_time), pnfsid);
+                }
+                if let Some(queuing_time) = queuing_time {
+                    observe_with_pnfsid(sampled(sample, &self.transfer_queuing_seconds), labels,
+                                        self.seconds(queuing_time), pnfsid);
+                    add(&self.transfer_total_queuing_seconds, labels, self.seconds(queuing_time));
+                }
+                observe(&self.transfer_file_size_bytes, labels, transfer_size as f64);
                 if let Some(bandwidth) = mean_read_bandwidth {
-                    proj(&self.transfer_mean_read_bandwidth_bytes_per_second, &msg)
-                        .observe(bandwidth);
+                    observe(&self.transfer_mean_read_bandwidth_bytes_per_second, labels, bandwidth);
                 }
                 if let Some(bandwidth) = mean_write_bandwidth {
-                    proj(&self.transfer_mean_write_bandwidth_bytes_per_second, &msg)
-                        .observe(bandwidth);
+                    observe(&self.transfer_mean_write_bandwidth_bytes_per_second, labels, bandwidth);
                 }
+                if let Some(active) = sum_durations(&[read_active, write_active]) {
+                    observe_with_pnfsid(sampled(sample, &self.transfer_active_seconds), labels,
+                                        active, pnfsid);
+                    add(&self.transfer_total_active_seconds, labels, active);
+                }
+                if let Some(idle) = sum_durations(&[read_idle, write_idle]) {
+                    observe_with_pnfsid(sampled(sample, &self.transfer_idle_seconds), labels,
+                                        idle, pnfsid);
+                    add(&self.transfer_total_idle_seconds, labels, idle);
+                }
+                let version_major = protocol_info.version_major.to_string();
+                self.inc_by_prepared("transfer_version", &self.transfer_version_count,
+                                     vec![protocol_info.protocol.as_str().into(),
+                                          version_major.as_str().into(),
+                                          protocol_info.version_minor.to_string().into()], 1);
+                self.inc_by_prepared("transfer_protocol", &self.transfer_protocol_count,
+                                     vec![protocol_info.protocol.as_str().into(),
+                                          version_major.as_str().into()], 1);
+            }
+        }
+    }
+
+    fn is_included(&self, msg: &Message) -> bool {
+        let domain = &msg.cell().domain;
+        (self.include_cell_domains.is_empty() ||
+         self.include_cell_domains.iter().any(|re| re.is_match(domain))) &&
+        !self.exclude_cell_domains.iter().any(|re| re.is_match(domain))
+    }
+
+    fn is_duplicate(&self, msg: &Message) -> bool {
+        let Some(recent_transactions) = &self.recent_transactions else {
+            return false;
+        };
+        let transaction = match msg {
+            Message::Remove {transaction: Some(transaction), ..} |
+            Message::Restore {transaction, ..} |
+            Message::Store {transaction, ..} => { transaction }
+            _ => { return false; }
+        };
+        recent_transactions.check_and_insert(transaction)
+    }
+
+    // Appends a rejected payload to the dead-letter file, if configured.
+    fn write_deadletter(&self, error: &dyn std::fmt::Display, payload: &str) {
+        let Some(deadletter) = &self.deadletter else {
+            return;
+        };
+        let payload = if self.anonymize_client_ip {
+            anonymize_ips(payload)
+        } else {
+            Cow::Borrowed(payload)
+        };
+        if let Err(error) = deadletter.write(&error.to_string(), &payload) {
+            warn!(error:%; "Failed to write to the dead-letter file.");
+        }
+    }
+
+    // Updates the metrics from a record, or from each record of a JSON array
+    // of records.  Returns the number of records parsed and counted.
+    pub fn process_message(&self, msg_str: &str, origin: &Origin) -> usize {
+        let start = Instant::now();
+        if let Some(debug_messages) = &self.debug_messages {
+            if self.anonymize_client_ip {
+                debug_messages.push(&anonymize_ips(msg_str));
+            } else {
+                debug_messages.push(msg_str);
             }
         }
+        let batch = if msg_str.trim_start().starts_with('[') {
+            serde_json::from_str::<Vec<serde_json::Value>>(msg_str).ok()
+        } else {
+            None
+        };
+        let parsed = match batch {
+            Some(records) => {
+                self.batch_count.inc();
+                records.iter()
+                    .filter(|record| self.parse_and_update(&record.to_string(), origin))
+                    .count()
+            }
+            None => { self.parse_and_update(msg_str, origin) as usize }
+        };
+        self.process_duration_seconds.observe(start.elapsed().as_secs_f64());
+        parsed
     }
 
-    pub fn process_message(&mut self, msg_str: &str) {
+    fn parse_and_update(&self, msg_str: &str, origin: &Origin) -> bool {
         match serde_json::from_str(msg_str) {
             Ok(msg) => {
-                self.update_metrics(msg);
+                if !self.is_included(&msg) {
+                    self.filtered_count.inc();
+                    return false;
+                }
+                if self.is_duplicate(&msg) {
+                    self.duplicate_count.inc();
+                    if let Message::Restore {..} | Message::Store {..} = msg {
+                        inc(&self.tape_retry_count, &[Self::kind(&msg)]);
+                    }
+                    return false;
+                }
+                debug!(topic = &*origin.topic, partition = origin.partition, offset = origin.offset,
+                       msg_type = Self::kind(&msg); "Processed record.");
+                self.update_metrics(msg, origin);
+                if let Ok(t) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                    self.last_message_timestamp_seconds.set(t.as_secs_f64());
+                }
+                true
             }
             Err(error) => {
-                warn!("Failed to parse JSON record {:?}: {:?}", msg_str, error);
-                self.unparsed_count.inc();
+                debug!(topic = &*origin.topic, partition = origin.partition,
+                       offset = origin.offset, error:%;
+                       "Failed to parse JSON record.");
+                // Records of message types we deliberately do not handle are
+                // not counted as parse failures.
+                let (is_object, msg_type) = match serde_json::from_str(msg_str) {
+                    Ok(MessageHeader {msg_type}) => { (true, msg_type) }
+                    Err(_) => { (false, None) }
+                };
+                if let Some(msg_type) = &msg_type {
+                    if self.ignored_msg_types.contains(msg_type) {
+                        self.ignored_count.with_label_values(&[msg_type]).inc();
+                        return false;
+                    }
+                }
+                if self.parse_warning_throttle.allow() {
+                    let record = if self.anonymize_client_ip {
+                        anonymize_ips(msg_str)
+                    } else {
+                        Cow::Borrowed(msg_str)
+                    };
+                    let msg_type = msg_type.as_deref().unwrap_or("");
+                    warn!(topic = &*origin.topic, partition = origin.partition,
+                          offset = origin.offset, msg_type, error:%, record = record.as_ref();
+                          "Failed to parse JSON record.");
+                }
+                self.write_deadletter(&error, msg_str);
+                // A JSON object lacking msgType is counted separately, as it
+                // indicates a broken producer rather than an outdated parser.
+                if is_object && msg_type.is_none() {
+                    self.missing_msgtype_count.inc();
+                } else {
+                    self.unparsed_count.with_label_values(&[parse_error_reason(&error)]).inc();
+                }
+                false
+            }
+        }
+    }
+
+    // Accounts a record consumed from a partition and returns where it came
+    // from, unless it should be skipped, as tombstones and oversize messages
+    // are.
+    pub fn accept(&self, topic: &Arc<str>, partition: i32, record: &Record) -> Option<Origin> {
+        // On a compacted topic, a record without a value marks the deletion
+        // of earlier records with the same key.  The Kafka client gives an
+        // empty value for these.
+        if record.value.is_empty() {
+            self.tombstone_count.inc();
+            return None;
+        }
+        self.message_bytes.observe(record.value.len() as f64);
+        if self.max_message_bytes > 0 && record.value.len() > self.max_message_bytes {
+            if self.oversize_warning_throttle.allow() {
+                warn!(topic = &**topic, partition, offset = record.offset,
+                      size = record.value.len();
+                      "Dropping oversize message.");
             }
+            self.oversize_dropped_count.inc();
+            return None;
+        }
+        let key = if self.enable_key_label {
+            str::from_utf8(&record.key).unwrap_or("").to_string()
+        } else {
+            String::new()
+        };
+        Some(Origin {topic: Arc::clone(topic), partition, offset: record.offset, key})
+    }
+
+    // Accounts the records of a batch as consumed.
+    pub fn count_consumed(&self, batch: &Batch) {
+        if let Some(last) = batch.records.last() {
+            let partition = batch.partition.to_string();
+            let labels = [&*batch.topic, partition.as_str()];
+            self.consumed_count.with_label_values(&labels).inc_by(batch.records.len() as u64);
+            self.current_offset.with_label_values(&labels).set(last.offset);
+        }
+    }
+
+    pub fn process_payload(&self, payload: &[u8], origin: &Origin) -> usize {
+        let decompressed;
+        let payload = match self.message_compression {
+            MessageCompression::None => { payload }
+            MessageCompression::Gzip => {
+                // Limit the decompressed size as well, so that a small message
+                // cannot expand to exhaust the memory.
+                let limit = match self.max_message_bytes {
+                    0 => { u64::MAX }
+                    max => { max as u64 + 1 }
+                };
+                let mut buf = vec![];
+                if let Err(error) = GzDecoder::new(payload).take(limit).read_to_end(&mut buf) {
+                    if self.parse_warning_throttle.allow() {
+                        warn!(topic = &*origin.topic, partition = origin.partition,
+                              offset = origin.offset, error:%;
+                              "Failed to decompress record.");
+                    }
+                    self.write_deadletter(&error, &String::from_utf8_lossy(payload));
+                    self.unparsed_count.with_label_values(&["compression"]).inc();
+                    return 0;
+                }
+                if self.max_message_bytes > 0 && buf.len() > self.max_message_bytes {
+                    if self.oversize_warning_throttle.allow() {
+                        warn!(topic = &*origin.topic, partition = origin.partition,
+                              offset = origin.offset;
+                              "Dropping message which is oversize when decompressed.");
+                    }
+                    self.oversize_dropped_count.inc();
+                    return 0;
+                }
+                decompressed = buf;
+                &decompressed
+            }
+        };
+        match str::from_utf8(payload) {
+            Ok(msg_str) => {
+                self.process_message(msg_str, origin)
+            }
+            Err(error) => {
+                if self.parse_warning_throttle.allow() {
+                    warn!(topic = &*origin.topic, partition = origin.partition,
+                          offset = origin.offset, error:%;
+                          "Failed to decode record as UTF-8.");
+                }
+                self.write_deadletter(&error, &String::from_utf8_lossy(payload));
+                self.unparsed_count.with_label_values(&["encoding"]).inc();
+                0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use super::*;
+
+    const REMOVE : &str = r#"{"msgType": "remove", "billingPath": "/", "cellName": "pool1",
+        "cellType": "pool", "cellDomain": "poolDomain", "date": "d", "fileSize": 1000,
+        "pnfsid": "0000ABCD", "queuingTime": 3, "session": "s",
+        "status": {"code": 0, "msg": ""}, "storageInfo": STORAGE_INFO, "subject": []}"#;
+
+    const REQUEST : &str = r#"{"msgType": "request", "billingPath": "/",
+        "cellName": "WebDAV-host", "cellType": "door", "cellDomain": "webdavDomain",
+        "client": "192.0.2.1", "clientChain": "192.0.2.1", "fileSize": 1000,
+        "mappedGID": 1000, "mappedUID": 1000, "moverInfo": MOVER_INFO, "owner": null,
+        "pnfsid": "0000ABCD", "queuingTime": 3, "session": "s", "sessionDuration": 500,
+        "status": {"code": 0, "msg": ""}, "storageInfo": "a:b@osm", "subject": [],
+        "transferPath": "/data/file"}"#;
+
+    const MOVER_INFO : &str = r#"{"msgType": "transfer", "cellName": "pool1",
+        "cellType": "pool", "cellDomain": "poolDomain", "date": "d", "isP2p": false,
+        "isWrite": "read", "localEndpoint": "192.0.2.2:2000", "meanReadBandwidth": null,
+        "meanWriteBandwidth": null,
+        "protocolInfo": {"host": "192.0.2.1", "port": 1234, "protocol": "Http",
+                         "versionMajor": 1, "versionMinor": 1},
+        "queuingTime": 3, "readActive": null, "readIdle": null, "session": "s",
+        "status": {"code": 0, "msg": ""}, "transferPath": "/data/file",
+        "transferSize": TRANSFER_SIZE, "transferTime": 2000, "version": "1"}"#;
+
+    const RESTORE : &str = r#"{"msgType": "restore", "billingPath": "/", "cellName": "pool1",
+        "cellType": "pool", "cellDomain": "poolDomain", "date": "d", "fileSize": 1000,
+        "hsm": {"instance": "osm", "provider": "script", "type": "osm"},
+        "locations": ["osm://a"], "pnfsid": "0000ABCD", "queuingTime": 3, "session": "s",
+        "status": STATUS, "storageInfo": "a:b@osm", "transaction": "t",
+        "transferTime": 5000, "version": "1"}"#;
+
+    const P2P_TRANSFER : &str = r#"{"msgType": "transfer", "billingPath": "/",
+        "cellName": "pool1", "cellType": "pool", "cellDomain": "poolDomain", "date": "d",
+        "fileSize": 1000, "initiator": "INITIATOR", "isP2p": true, "isWrite": "read",
+        "localEndpoint": null, "meanReadBandwidth": null, "meanWriteBandwidth": null,
+        "pnfsid": "0000ABCD",
+        "protocolInfo": {"host": "192.0.2.1", "port": 1234, "protocol": "DCap",
+                         "versionMajor": 3, "versionMinor": 0},
+        "queuingTime": 3, "readActive": null, "readIdle": null, "session": "s",
+        "transferTime": 2000, "storageInfo": "a:b@osm", "transferSize": 1000,
+        "transferPath": "/data/file", "writeActive": null, "writeIdle": null,
+        "subject": []}"#;
+
+    fn collector(registry: &Registry) -> Collector {
+        Collector::new(Config::default(), registry).unwrap()
+    }
+
+    fn process(collector: &Collector, msg: &str) -> usize {
+        let origin = Origin {
+            topic: Arc::from("billing"), partition: 0, offset: 0, key: String::new(),
+        };
+        collector.process_message(msg, &origin)
+    }
+
+    // The sum over the series of a counter, or zero if it has none.
+    fn total(registry: &Registry, name: &str) -> f64 {
+        registry.gather().iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value())
+            .sum()
+    }
+
+    // The cell_name label and value of each series of a counter or gauge.
+    fn by_cell_name(registry: &Registry, name: &str) -> Vec<(String, f64)> {
+        registry.gather().iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let cell_name = metric.get_label().iter()
+                    .find(|label| label.get_name() == "cell_name")
+                    .map_or(String::new(), |label| label.get_value().to_string());
+                (cell_name, metric.get_counter().get_value() + metric.get_gauge().get_value())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn request_with_mover() {
+        let registry = Registry::new();
+        let collector = collector(&registry);
+        let msg = REQUEST.replace("MOVER_INFO", MOVER_INFO).replace("TRANSFER_SIZE", "1000");
+        assert_eq!(process(&collector, &msg), 1);
+        assert_eq!(total(&registry, "dcache_kafka_request_read_bytes"), 1000.0);
+    }
+
+    #[test]
+    fn request_with_unknown_mover_size() {
+        let registry = Registry::new();
+        let collector = collector(&registry);
+        let msg = REQUEST.replace("MOVER_INFO", MOVER_INFO).replace("TRANSFER_SIZE", "-1");
+        assert_eq!(process(&collector, &msg), 1);
+        assert_eq!(total(&registry, "dcache_kafka_request_count"), 1.0);
+        assert_eq!(total(&registry, "dcache_kafka_request_read_bytes"), 0.0);
+        assert_eq!(total(&registry, "dcache_kafka_request_write_bytes"), 0.0);
+        assert_eq!(total(&registry, "dcache_kafka_unparsed_count"), 0.0);
+    }
+
+    #[test]
+    fn missing_msg_type() {
+        let registry = Registry::new();
+        let collector = collector(&registry);
+        assert_eq!(process(&collector, "{}"), 0);
+        assert_eq!(process(&collector, r#"{"cellName": "pool1"}"#), 0);
+        assert_eq!(total(&registry, "dcache_kafka_missing_msgtype_count"), 2.0);
+        assert_eq!(total(&registry, "dcache_kafka_unparsed_count"), 0.0);
+    }
+
+    fn rewriting_collector(registry: &Registry, rewrites: &[(&str, &str)]) -> Collector {
+        let config = Config {
+            cell_name_rewrites: rewrites.iter()
+                .map(|(re, replacement)| (Regex::new(re).unwrap(), replacement.to_string()))
+                .collect(),
+            ..Config::default()
+        };
+        Collector::new(config, registry).unwrap()
+    }
+
+    #[test]
+    fn cell_name_capture_groups() {
+        let registry = Registry::new();
+        let collector = rewriting_collector(&registry, &[
+            (r"^WebDAV-(\w+)-\d+$", "WebDAV-$1"),
+            (r"^(?P<name>dcap)-.*$", "${name}-door"),
+        ]);
+        assert_eq!(collector.cell_name_label("WebDAV-host-42"), "WebDAV-host");
+        assert_eq!(collector.cell_name_label("dcap-host-7"), "dcap-door");
+        assert_eq!(collector.cell_name_label("pool1"), "pool1");
+    }
+
+    #[test]
+    fn cell_name_first_match_wins() {
+        let registry = Registry::new();
+        let collector = rewriting_collector(&registry, &[
+            (r"^WebDAV-.*$", "WebDAV"),
+            (r"^WebDAV-(\w+)$", "$1"),
+        ]);
+        assert_eq!(collector.cell_name_label("WebDAV-host"), "WebDAV");
+    }
+
+    #[test]
+    fn oversize_when_decompressed() {
+        let registry = Registry::new();
+        let config = Config {
+            message_compression: MessageCompression::Gzip,
+            max_message_bytes: 100,
+            ..Config::default()
+        };
+        let collector = Collector::new(config, &registry).unwrap();
+        let origin = Origin {
+            topic: Arc::from("billing"), partition: 0, offset: 0, key: String::new(),
+        };
+        for msg in ["{}".to_string(), format!("{:1000}", "{}")] {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(msg.as_bytes()).unwrap();
+            let payload = encoder.finish().unwrap();
+            assert!(payload.len() < 100);
+            assert_eq!(collector.process_payload(&payload, &origin), 0);
         }
+        assert_eq!(total(&registry, "dcache_kafka_missing_msgtype_count"), 1.0);
+        assert_eq!(total(&registry, "dcache_kafka_oversize_dropped_count"), 1.0);
+        assert_eq!(total(&registry, "dcache_kafka_unparsed_count"), 0.0);
+    }
+
+    #[test]
+    fn series_overflow() {
+        let registry = Registry::new();
+        let config = Config {
+            max_series: Some(3),
+            enable_max_recent_queuing: true,
+            ..Config::default()
+        };
+        let collector = Collector::new(config, &registry).unwrap();
+        // The series of the restores, by status code, and of their queuing
+        // time fill the limit.
+        let restored = RESTORE.replace("STATUS", r#"{"code": 0, "msg": ""}"#);
+        assert_eq!(process(&collector, &restored), 1);
+        let failed = RESTORE.replace("STATUS", r#"{"code": 1, "msg": "tape error"}"#);
+        assert_eq!(process(&collector, &failed), 1);
+        assert_eq!(by_cell_name(&registry, "dcache_kafka_restore_count"),
+                   [("pool1".to_string(), 1.0), ("pool1".to_string(), 1.0)]);
+        assert_eq!(by_cell_name(&registry, "dcache_kafka_restore_failed_count"),
+                   [(OVERFLOW_LABEL_VALUE.to_string(), 1.0)]);
+        // A request from another cell overflows for its counts and queuing
+        // time.
+        let msg = REQUEST.replace("MOVER_INFO", MOVER_INFO).replace("TRANSFER_SIZE", "1000");
+        assert_eq!(process(&collector, &msg), 1);
+        assert_eq!(by_cell_name(&registry, "dcache_kafka_request_read_bytes"),
+                   [(OVERFLOW_LABEL_VALUE.to_string(), 1000.0)]);
+        assert_eq!(by_cell_name(&registry, "dcache_kafka_max_recent_queuing_seconds"),
+                   [(OVERFLOW_LABEL_VALUE.to_string(), 0.003), ("pool1".to_string(), 0.003)]);
+        assert_eq!(total(&registry, "dcache_kafka_series_overflow_count"), 3.0);
+    }
+
+    #[test]
+    fn protocol_overflow() {
+        let registry = Registry::new();
+        let config = Config {
+            max_series: Some(2),
+            label_ttl: Some(Duration::ZERO),
+            ..Config::default()
+        };
+        let collector = Collector::new(config, &registry).unwrap();
+        // The transfer and its protocol fill the limit, so that the protocol
+        // of the next transfer overflows.
+        let transfer = P2P_TRANSFER.replace("INITIATOR", "door:dcap@dcapDomain:1")
+            .replace(r#""isP2p": true"#, r#""isP2p": false"#);
+        assert_eq!(process(&collector, &transfer), 1);
+        let other = transfer.replace(r#""protocol": "DCap""#, r#""protocol": "Xrootd""#);
+        assert_eq!(process(&collector, &other), 1);
+        let mut protocols: Vec<(String, f64)> = registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_transfer_protocol_count")
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                (metric.get_label()[0].get_value().to_string(), metric.get_counter().get_value())
+            })
+            .collect();
+        protocols.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(protocols, [(OVERFLOW_LABEL_VALUE.to_string(), 1.0),
+                               ("DCap".to_string(), 1.0)]);
+        collector.expire_labels();
+        assert!(registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_transfer_protocol_count")
+            .all(|family| family.get_metric().is_empty()));
+    }
+
+    #[test]
+    fn version_overflow() {
+        let registry = Registry::new();
+        let config = Config {
+            max_series: Some(3),
+            label_ttl: Some(Duration::ZERO),
+            enable_version_metric: true,
+            ..Config::default()
+        };
+        let collector = Collector::new(config, &registry).unwrap();
+        // The transfer, its protocol and its version fill the limit, so that
+        // the version of the next transfer overflows.
+        let transfer = P2P_TRANSFER.replace("INITIATOR", "door:dcap@dcapDomain:1")
+            .replace(r#""isP2p": true"#, r#""isP2p": false"#);
+        assert_eq!(process(&collector, &transfer), 1);
+        let other = transfer.replace(r#""versionMinor": 0"#, r#""versionMinor": 1"#);
+        assert_eq!(process(&collector, &other), 1);
+        let mut versions: Vec<(String, f64)> = registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_transfer_version_count")
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let minor = metric.get_label().iter()
+                    .find(|label| label.get_name() == "version_minor").unwrap();
+                (minor.get_value().to_string(), metric.get_counter().get_value())
+            })
+            .collect();
+        versions.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(versions, [("0".to_string(), 1.0), (OVERFLOW_LABEL_VALUE.to_string(), 1.0)]);
+        collector.expire_labels();
+        assert!(registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_transfer_version_count")
+            .all(|family| family.get_metric().is_empty()));
+    }
+
+    #[test]
+    fn expire_failures() {
+        let registry = Registry::new();
+        let config = Config {label_ttl: Some(Duration::ZERO), ..Config::default()};
+        let collector = Collector::new(config, &registry).unwrap();
+        let failed = RESTORE.replace("STATUS", r#"{"code": 1, "msg": "tape error"}"#);
+        assert_eq!(process(&collector, &failed), 1);
+        assert_eq!(by_cell_name(&registry, "dcache_kafka_restore_failed_count").len(), 1);
+        collector.expire_labels();
+        assert!(by_cell_name(&registry, "dcache_kafka_restore_count").is_empty());
+        assert!(by_cell_name(&registry, "dcache_kafka_restore_failed_count").is_empty());
+    }
+
+    #[test]
+    fn p2p_pools() {
+        let registry = Registry::new();
+        let collector = collector(&registry);
+        for initiator in ["pool:pool2@poolDomain:1700000000000", "migration:m1@poolDomain"] {
+            assert_eq!(process(&collector, &P2P_TRANSFER.replace("INITIATOR", initiator)), 1);
+        }
+        let mut series: Vec<(Vec<String>, u64)> = registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_transfer_p2p_bytes")
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let labels = metric.get_label().iter()
+                    .map(|label| format!("{}={}", label.get_name(), label.get_value()))
+                    .collect();
+                (labels, metric.get_counter().get_value() as u64)
+            })
+            .collect();
+        series.sort();
+        assert_eq!(series, [
+            (vec!["dest_pool=pool2".to_string(), "source_pool=pool1".to_string()], 1000),
+            (vec!["dest_pool=unknown".to_string(), "source_pool=pool1".to_string()], 1000),
+        ]);
+        assert_eq!(total(&registry, "dcache_kafka_transfer_p2p_count"), 2.0);
+    }
+
+    #[test]
+    fn truncate_p2p_pools() {
+        let registry = Registry::new();
+        let config = Config {max_label_length: 10, ..Config::default()};
+        let collector = Collector::new(config, &registry).unwrap();
+        let msg = P2P_TRANSFER
+            .replace(r#""cellName": "pool1""#, r#""cellName": "pool-with-a-long-name""#)
+            .replace("INITIATOR", "pool:pool2@poolDomain:1700000000000");
+        assert_eq!(process(&collector, &msg), 1);
+        let pools: Vec<(String, String)> = registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_transfer_p2p_bytes")
+            .flat_map(|family| family.get_metric())
+            .flat_map(|metric| metric.get_label())
+            .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+            .collect();
+        assert_eq!(pools, [("dest_pool".to_string(), "pool2".to_string()),
+                           ("source_pool".to_string(), "pool-with…".to_string())]);
+        let truncated: Vec<(String, f64)> = registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_label_truncated_count")
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                (metric.get_label()[0].get_value().to_string(), metric.get_counter().get_value())
+            })
+            .collect();
+        assert_eq!(truncated, [("cell_name".to_string(), 1.0), ("source_pool".to_string(), 1.0)]);
+    }
+    #[test]
+    fn truncate_storage_class() {
+        let registry = Registry::new();
+        let config = Config {
+            max_label_length: 10,
+            label_ttl: Some(Duration::ZERO),
+            ..Config::default()
+        };
+        let collector = Collector::new(config, &registry).unwrap();
+        let msg = REMOVE.replace("STORAGE_INFO", r#""a-long-storage-group:b@osm""#);
+        assert_eq!(process(&collector, &msg), 1);
+        let classes: Vec<(Vec<String>, f64)> = registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_remove_storage_class_bytes")
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let labels = metric.get_label().iter()
+                    .map(|label| label.get_value().to_string())
+                    .collect();
+                (labels, metric.get_counter().get_value())
+            })
+            .collect();
+        assert_eq!(classes, [(vec!["b".to_string(), "a-long-st…".to_string()], 1000.0)]);
+        let truncated: Vec<(String, f64)> = registry.gather().iter()
+            .filter(|family| family.get_name() == "dcache_kafka_label_truncated_count")
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                (metric.get_label()[0].get_value().to_string(), metric.get_counter().get_value())
+            })
+            .collect();
+        assert_eq!(truncated, [("storage_group".to_string(), 1.0),
+                               ("storage_info".to_string(), 1.0)]);
+        collector.expire_labels();
+        assert_eq!(total(&registry, "dcache_kafka_remove_storage_class_bytes"), 0.0);
+    }
+    #[test]
+    fn restore_counters() {
+        let registry = Registry::new();
+        let original = collector(&registry);
+        let msg = REQUEST.replace("MOVER_INFO", MOVER_INFO).replace("TRANSFER_SIZE", "1000");
+        assert_eq!(process(&original, &msg), 1);
+        original.consumed_count.with_label_values(&["billing", "0"]).inc();
+        original.oversize_dropped_count.inc();
+        original.tombstone_count.inc();
+        let saved = state::snapshot(&original.counters());
+        let restored_registry = Registry::new();
+        let restored = collector(&restored_registry);
+        assert_eq!(state::restore(&restored.counters(), &saved), 0);
+        let snapshot = |collector: &Collector| {
+            serde_json::to_value(state::snapshot(&collector.counters())).unwrap()
+        };
+        assert_eq!(snapshot(&restored), snapshot(&original));
+        assert_eq!(total(&restored_registry, "dcache_kafka_consumed_count"), 1.0);
+        assert_eq!(total(&restored_registry, "dcache_kafka_tombstone_count"), 1.0);
     }
 }
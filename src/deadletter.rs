@@ -0,0 +1,81 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Appending records which could not be parsed to a file for later analysis.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: f64,
+    error: &'a str,
+    payload: &'a str,
+}
+
+// A file of unparsed payloads, one JSON object per line.  When appending an
+// entry would exceed the maximum size, the file is renamed with the suffix
+// ".1", replacing any previous such file, and a new file is started.
+pub struct DeadLetterFile {
+    path: PathBuf,
+    max_bytes: u64,
+    state: Mutex<DeadLetterState>,
+}
+
+struct DeadLetterState {
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl DeadLetterFile {
+    pub fn open(path: PathBuf, max_bytes: u64) -> io::Result<DeadLetterFile> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(DeadLetterFile {path, max_bytes, state: Mutex::new(DeadLetterState {file, size})})
+    }
+
+    fn rotate(&self, state: &mut DeadLetterState) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        state.file = open_append(&self.path)?;
+        state.size = 0;
+        Ok(())
+    }
+
+    // Appends a payload along with the reason it was rejected.
+    pub fn write(&self, error: &str, payload: &str) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64()).unwrap_or(0.0);
+        let mut line = serde_json::to_vec(&Entry {timestamp, error, payload})?;
+        line.push(b'\n');
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.size > 0 && state.size + line.len() as u64 > self.max_bytes {
+            self.rotate(&mut state)?;
+        }
+        state.file.write_all(&line)?;
+        state.size += line.len() as u64;
+        Ok(())
+    }
+}
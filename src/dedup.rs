@@ -0,0 +1,59 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Mutex, PoisonError};
+
+// Remembers the most recently seen ids, up to a fixed number, to detect
+// records which are delivered more than once.
+pub struct RecentIds {
+    capacity: usize,
+    state: Mutex<RecentIdsState>,
+}
+
+struct RecentIdsState {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> RecentIds {
+        RecentIds {
+            capacity,
+            state: Mutex::new(RecentIdsState {
+                ids: HashSet::with_capacity(capacity),
+                order: VecDeque::with_capacity(capacity),
+            }),
+        }
+    }
+
+    // Records the id and returns true if it was already among the recent
+    // ones.
+    pub fn check_and_insert(&self, id: &str) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.ids.contains(id) {
+            return true;
+        }
+        if state.order.len() == self.capacity {
+            if let Some(oldest) = state.order.pop_front() {
+                state.ids.remove(&oldest);
+            }
+        }
+        state.ids.insert(id.to_string());
+        state.order.push_back(id.to_string());
+        false
+    }
+}
@@ -0,0 +1,113 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// dCache serializes java.time.Duration values as ISO-8601 durations, like
+// "PT1.5S", "PT2M3.25S", or "PT-0.5S".  Days are accepted for robustness,
+// though Java never emits them.  Years and months are rejected, since their
+// lengths are not fixed.
+
+// Parses an ISO-8601 duration into seconds.
+pub fn parse_duration(s: &str) -> Option<f64> {
+    let (sign, s) = match s.strip_prefix('-') {
+        Some(s) => { (-1.0, s) }
+        None => { (1.0, s.strip_prefix('+').unwrap_or(s)) }
+    };
+    let s = s.strip_prefix(['P', 'p'])?;
+    let (date, time) = match s.split_once(['T', 't']) {
+        Some((date, time)) => {
+            if time.is_empty() {
+                return None;
+            }
+            (date, time)
+        }
+        None => { (s, "") }
+    };
+    if date.is_empty() && time.is_empty() {
+        return None;
+    }
+    let days = parse_components(date, &[('D', 86400.0)])?;
+    let time = parse_components(time, &[('H', 3600.0), ('M', 60.0), ('S', 1.0)])?;
+    Some(sign * (days + time))
+}
+
+// Parses a sequence of number-designator pairs, where the designators must
+// occur in the order given by units, each at most once.
+fn parse_components(s: &str, units: &[(char, f64)]) -> Option<f64> {
+    let mut total = 0.0;
+    let mut rest = s;
+    let mut units = units.iter();
+    while !rest.is_empty() {
+        let end = rest.find(|c: char| c.is_ascii_alphabetic())?;
+        let (number, designator) = (&rest[..end], rest[end..].chars().next()?);
+        if number.is_empty() || number.contains(|c: char| c.is_whitespace()) {
+            return None;
+        }
+        let value: f64 = number.replace(',', ".").parse().ok()?;
+        let &(_, scale) =
+            units.find(|(u, _)| *u == designator.to_ascii_uppercase())?;
+        total += value * scale;
+        rest = &rest[end + 1..];
+    }
+    total.is_finite().then_some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+
+    #[test]
+    fn seconds_and_minutes() {
+        assert_eq!(parse_duration("PT1.5S"), Some(1.5));
+        assert_eq!(parse_duration("PT2M3.25S"), Some(123.25));
+        assert_eq!(parse_duration("PT1H"), Some(3600.0));
+    }
+
+    #[test]
+    fn negative() {
+        assert_eq!(parse_duration("PT-0.5S"), Some(-0.5));
+        assert_eq!(parse_duration("-PT2S"), Some(-2.0));
+        assert_eq!(parse_duration("-PT1M-30S"), Some(-30.0));
+    }
+
+    #[test]
+    fn days() {
+        assert_eq!(parse_duration("P1D"), Some(86400.0));
+        assert_eq!(parse_duration("P1DT1S"), Some(86401.0));
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("P"), None);
+        assert_eq!(parse_duration("PT"), None);
+        assert_eq!(parse_duration("P1DT"), None);
+    }
+
+    #[test]
+    fn designator_order() {
+        assert_eq!(parse_duration("PT1S2M"), None);
+        assert_eq!(parse_duration("PT1S1S"), None);
+        assert_eq!(parse_duration("PT1H1H"), None);
+        assert_eq!(parse_duration("PT1D"), None);
+    }
+
+    #[test]
+    fn years_and_months() {
+        assert_eq!(parse_duration("P1Y"), None);
+        assert_eq!(parse_duration("P1M"), None);
+        assert_eq!(parse_duration("P1Y2M3DT4S"), None);
+    }
+}
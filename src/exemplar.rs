@@ -0,0 +1,98 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The prometheus crate has no exemplar support, so exemplars are tracked
+// here alongside the histograms and picked up by the OpenMetrics encoder.
+// Only the most recent exemplar of each bucket is kept.
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use prometheus_exporter::prometheus::proto::LabelPair;
+
+#[derive(Clone)]
+pub struct Exemplar {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub timestamp: f64,
+}
+
+// The exemplars of a histogram family, indexed by label values in the order
+// of label_names, then by bucket, with the +Inf bucket last.
+pub struct ExemplarFamily {
+    label_names: Vec<String>,
+    upper_bounds: Vec<f64>,
+    exemplars: Mutex<HashMap<Vec<String>, Vec<Option<Exemplar>>>>,
+}
+
+static FAMILIES: LazyLock<Mutex<HashMap<String, Arc<ExemplarFamily>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Starts tracking exemplars for the histogram of the given name.
+pub fn register(name: &str, label_names: &[&str], upper_bounds: &[f64]) -> Arc<ExemplarFamily> {
+    let family = Arc::new(ExemplarFamily {
+        label_names: label_names.iter().map(|s| s.to_string()).collect(),
+        upper_bounds: upper_bounds.to_vec(),
+        exemplars: Mutex::new(HashMap::new()),
+    });
+    FAMILIES.lock().unwrap_or_else(PoisonError::into_inner)
+        .insert(name.to_string(), Arc::clone(&family));
+    family
+}
+
+impl ExemplarFamily {
+    pub fn observe(&self, label_values: &[&str], value: f64, labels: &[(&str, &str)]) {
+        let bucket = self.upper_bounds.iter()
+            .position(|&upper_bound| value <= upper_bound)
+            .unwrap_or(self.upper_bounds.len());
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64()).unwrap_or(0.0);
+        let exemplar = Exemplar {
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            value,
+            timestamp,
+        };
+        let key = label_values.iter().map(|s| s.to_string()).collect();
+        let mut exemplars = self.exemplars.lock().unwrap_or_else(PoisonError::into_inner);
+        let buckets = exemplars.entry(key)
+            .or_insert_with(|| (0..=self.upper_bounds.len()).map(|_| None).collect());
+        buckets[bucket] = Some(exemplar);
+    }
+}
+
+impl ExemplarFamily {
+    pub fn remove(&self, label_values: &[&str]) {
+        let key: Vec<String> = label_values.iter().map(|s| s.to_string()).collect();
+        self.exemplars.lock().unwrap_or_else(PoisonError::into_inner).remove(&key);
+    }
+}
+
+// The exemplar of the given bucket of a histogram, if any.
+pub fn lookup(name: &str, label_pairs: &[LabelPair], upper_bound: f64) -> Option<Exemplar> {
+    let family = FAMILIES.lock().unwrap_or_else(PoisonError::into_inner).get(name).cloned()?;
+    let key: Vec<String> = family.label_names.iter()
+        .map(|name| {
+            label_pairs.iter()
+                .find(|lp| lp.get_name() == name)
+                .map(|lp| lp.get_value().to_string())
+        })
+        .collect::<Option<_>>()?;
+    let bucket = family.upper_bounds.iter()
+        .position(|&b| b == upper_bound)
+        .unwrap_or(family.upper_bounds.len());
+    let exemplars = family.exemplars.lock().unwrap_or_else(PoisonError::into_inner);
+    exemplars.get(&key)?[bucket].clone()
+}
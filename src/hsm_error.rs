@@ -0,0 +1,77 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Classification of the error messages of failed restores and stores, which
+// mostly come from the HSM scripts, into a few classes suitable as a label.
+
+use std::path::Path;
+use regex::Regex;
+
+// Patterns matched in order against the status message, so that more
+// specific patterns come first.
+const BUILTIN_CLASSES : &[(&str, &str)] = &[
+    (r"(?i)drives? (?:is |are )?busy|no (?:free |available )?drives?|all drives", "drive-busy"),
+    (r"(?i)media error|medium error|bad tape|tape (?:is )?damaged|i/o error", "media-error"),
+    (r"(?i)not found|no such file|does not exist|enoent", "not-found"),
+    (r"(?i)timed? ?out|timeout", "timeout"),
+    (r"(?i)permission denied|not authori[sz]ed|eacces", "permission-denied"),
+    (r"(?i)no space|disk full|quota exceeded", "no-space"),
+];
+
+pub struct HsmErrorClasses {
+    classes: Vec<(Regex, String)>,
+}
+
+impl HsmErrorClasses {
+    pub fn builtin() -> HsmErrorClasses {
+        let classes = BUILTIN_CLASSES.iter()
+            .map(|(pattern, class)| (Regex::new(pattern).unwrap(), class.to_string()))
+            .collect();
+        HsmErrorClasses {classes}
+    }
+
+    // Adds patterns from a file where each line holds a regular expression
+    // and a class separated by a tab.  Empty lines and lines starting with
+    // "#" are ignored.  The patterns are tried before those already present.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        let mut classes = vec![];
+        for (lineno, line) in content.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |msg: String| format!("{}:{}: {}", path.display(), lineno + 1, msg);
+            let (pattern, class) = line.split_once('\t')
+                .ok_or_else(|| error("expected a regular expression, a tab, and a class"
+                                     .to_string()))?;
+            let regex = Regex::new(pattern).map_err(|err| error(err.to_string()))?;
+            classes.push((regex, class.trim().to_string()));
+        }
+        classes.append(&mut self.classes);
+        self.classes = classes;
+        Ok(())
+    }
+
+    // The class of the first pattern matching a status message, or "other"
+    // if none does.
+    pub fn classify(&self, msg: &str) -> &str {
+        self.classes.iter()
+            .find(|(regex, _)| regex.is_match(msg))
+            .map(|(_, class)| class.as_str())
+            .unwrap_or("other")
+    }
+}
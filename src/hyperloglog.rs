@@ -0,0 +1,69 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A HyperLogLog sketch for estimating the number of distinct strings seen,
+// using 16 KiB regardless of the number.  The standard error of the estimate
+// is about 0.8%.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+// The number of bits of the hash used to select a register.
+const PRECISION : u32 = 14;
+const REGISTER_COUNT : usize = 1 << PRECISION;
+
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> HyperLogLog {
+        HyperLogLog {registers: vec![0; REGISTER_COUNT]}
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert(&mut self, item: &str) {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // The position of the first set bit of the remaining bits, counting
+        // from 1.  The sentinel bit bounds it if they are all zero.
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.registers.fill(0);
+    }
+
+    // The estimated number of distinct items inserted, with the linear
+    // counting correction for small numbers.
+    pub fn estimate(&self) -> f64 {
+        let m = REGISTER_COUNT as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| (-(r as f64)).exp2()).sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
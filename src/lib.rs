@@ -0,0 +1,47 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The exporter as a library, so that message processing can be exercised
+// without Kafka, e.g. by the benchmarks.  See src/main.rs for the program.
+
+pub mod cell_category;
+pub mod collector;
+pub mod deadletter;
+pub mod hsm_error;
+pub mod logging;
+pub mod message_simplifier;
+pub mod metadata;
+pub mod metric_names;
+pub mod process;
+pub mod rate;
+pub mod recent;
+pub mod remote_write;
+pub mod schema;
+pub mod server;
+pub mod source;
+pub mod state;
+pub mod status;
+pub mod throttle;
+
+mod anonymize;
+mod billing;
+mod dedup;
+mod duration;
+mod exemplar;
+mod hyperloglog;
+mod openmetrics;
+mod subject;
+mod summary;
@@ -0,0 +1,82 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io::Write;
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::{Map, json};
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum LogFormat {Text, Json}
+
+// A key-value as a JSON boolean or number where it is one, otherwise as a
+// string.
+fn json_value(value: &Value) -> serde_json::Value {
+    if let Some(b) = value.to_bool() {
+        json!(b)
+    } else if let Some(n) = value.to_u64() {
+        json!(n)
+    } else if let Some(n) = value.to_i64() {
+        json!(n)
+    } else if let Some(x) = value.to_f64().filter(|x| x.is_finite()) {
+        json!(x)
+    } else {
+        json!(value.to_string())
+    }
+}
+
+// Collects the key-values of a record as JSON fields.
+struct JsonFields(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), json_value(&value));
+        Ok(())
+    }
+}
+
+// Installs the logger, configured from RUST_LOG as usual.  The JSON format
+// emits one object per line with the key-values of the record as fields.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let mut fields = JsonFields(Map::new());
+            fields.0.insert("timestamp".into(), json!(buf.timestamp().to_string()));
+            fields.0.insert("level".into(), json!(record.level().as_str()));
+            fields.0.insert("target".into(), json!(record.target()));
+            fields.0.insert("message".into(), json!(record.args().to_string()));
+            record.key_values().visit(&mut fields).map_err(std::io::Error::other)?;
+            writeln!(buf, "{}", serde_json::Value::Object(fields.0))
+        });
+    }
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn native_json_values() {
+        assert_eq!(json_value(&Value::from(true)), json!(true));
+        assert_eq!(json_value(&Value::from(42u64)), json!(42));
+        assert_eq!(json_value(&Value::from(-1i64)), json!(-1));
+        assert_eq!(json_value(&Value::from(0.5f64)), json!(0.5));
+        assert_eq!(json_value(&Value::from(f64::NAN)), json!("NaN"));
+        assert_eq!(json_value(&Value::from("42")), json!("42"));
+        assert_eq!(json_value(&Value::from_display(&"pool1")), json!("pool1"));
+    }
+}
@@ -14,75 +14,1340 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::str;
-use clap::Parser;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::io::Write;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex, PoisonError, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use clap::parser::ValueSource;
 use std::error::Error;
 use kafka::client::{KafkaClient, SecurityConfig};
 use kafka::consumer::{Consumer, FetchOffset};
+use log::{error, info, warn};
+use cell_category::CellCategories;
+use deadletter::DeadLetterFile;
+use hsm_error::HsmErrorClasses;
+use message_simplifier::MessageRewriteRules;
+use metadata::MetadataRefresh;
+use metric_names::MetricNames;
+use process::ProcessCollector;
+use recent::RecentMessages;
+use source::KafkaSource;
+use status::StatusCategories;
+use throttle::LogThrottle;
+use openssl::pkcs12::Pkcs12;
+use regex::Regex;
 use openssl::ssl;
+use prometheus_exporter::prometheus::{
+    Encoder, TextEncoder, core, default_registry, gather,
+    register_int_counter, register_int_gauge, register_int_gauge_vec,
+};
 
-mod billing;
-mod collector;
+use dcache_kafka_exporter::{
+    cell_category, collector, deadletter, hsm_error, logging, message_simplifier, metadata,
+    metric_names, process, rate, recent, remote_write, schema, server, source, state, status,
+    throttle,
+};
 
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum FallbackOffset {Earliest, Latest}
+
+// Each option can also be given in an environment variable named after it
+// with a DKE_ prefix, e.g. DKE_KAFKA_HOSTS for --kafka-hosts.  Options given
+// on the command line take precedence.
 #[derive(Parser)]
 struct Args {
 
-    #[arg(long, value_delimiter = ',',
+    #[arg(long, env = "DKE_KAFKA_HOSTS", value_delimiter = ',',
           default_values_t = ["localhost:9092".to_string()])]
     kafka_hosts: Vec<String>,
 
-    #[arg(long = "kafka-ca", value_name = "PEM-FILE")]
+    // CA certificates to trust in addition to the system trust store, which
+    // is always used to verify the brokers.  This is either a PEM bundle or
+    // a directory of certificates named by subject hash, as prepared by
+    // openssl rehash.
+    #[arg(long = "kafka-ca", env = "DKE_KAFKA_CA", value_name = "PEM-FILE-OR-DIR")]
     ca_path: Option<std::path::PathBuf>,
 
-    #[arg(long = "client-key", value_name = "PEM-FILE")]
+    #[arg(long = "client-key", env = "DKE_CLIENT_KEY",
+          value_name = "PEM-FILE", requires = "cert_path")]
     key_path: Option<std::path::PathBuf>,
 
-    #[arg(long = "client-cert", value_name = "PEM-FILE")]
+    #[arg(long = "client-cert", env = "DKE_CLIENT_CERT",
+          value_name = "PEM-FILE", requires = "key_path")]
     cert_path: Option<std::path::PathBuf>,
 
-    #[arg(long, default_value = "billing")]
+    // A PKCS#12 bundle holding the client certificate, key, and any
+    // intermediate certificates, as an alternative to the PEM files.
+    #[arg(long = "client-pkcs12", env = "DKE_CLIENT_PKCS12", value_name = "FILE",
+          conflicts_with_all = ["cert_path", "key_path"])]
+    pkcs12_path: Option<std::path::PathBuf>,
+
+    // A file holding the password of the PKCS#12 bundle.  Without it, the
+    // bundle must have an empty password.
+    #[arg(long = "client-pkcs12-password-file", env = "DKE_CLIENT_PKCS12_PASSWORD_FILE",
+          value_name = "FILE", requires = "pkcs12_path")]
+    pkcs12_password_path: Option<std::path::PathBuf>,
+
+    // A directory of TLS material as mounted from a Kubernetes secret.  The
+    // files ca.pem, tls.crt, and tls.key are used for --kafka-ca,
+    // --client-cert, and --client-key if present, unless given by those
+    // options.  The client certificate and key are only used as a pair.
+    #[arg(long, env = "DKE_SECRETS_DIR", value_name = "DIR")]
+    secrets_dir: Option<std::path::PathBuf>,
+
+    #[arg(long, env = "DKE_KAFKA_TOPIC", default_value = "billing")]
     kafka_topic: String,
 
-    #[arg(long, default_value = "dcache-kafka-exporter")]
+    // Only consume these partitions of the topic, so that instances can
+    // share a topic.  All partitions are consumed by default.
+    #[arg(long, env = "DKE_PARTITIONS", value_name = "IDS", value_delimiter = ',')]
+    partitions: Vec<i32>,
+
+    #[arg(long, env = "DKE_KAFKA_GROUP", default_value = "dcache-kafka-exporter")]
     kafka_group: String,
 
-    #[arg(long, default_value = "dcache_kafka_")]
+    // Defaults to dcache-kafka-exporter@<hostname>.
+    #[arg(long, env = "DKE_KAFKA_CLIENT_ID", value_name = "ID")]
+    kafka_client_id: Option<String>,
+
+    // The maximum number of bytes to fetch from each partition per poll.
+    #[arg(long, env = "DKE_FETCH_MAX_BYTES", value_name = "BYTES", default_value_t = 32768)]
+    fetch_max_bytes: i32,
+
+    // The number of bytes the broker should wait for before answering a
+    // fetch, unless --poll-max-wait-ms expires first.
+    #[arg(long, env = "DKE_FETCH_MIN_BYTES", value_name = "BYTES", default_value_t = 4096)]
+    fetch_min_bytes: i32,
+
+    #[arg(long, env = "DKE_POLL_MAX_WAIT_MS", value_name = "MILLISECONDS", default_value_t = 100)]
+    poll_max_wait_ms: u64,
+
+    // How often to reload the Kafka cluster metadata to follow partition
+    // reassignments.  Zero disables reloading.
+    #[arg(long, env = "DKE_METADATA_REFRESH_SECONDS",
+          value_name = "SECONDS", default_value_t = 300)]
+    metadata_refresh_seconds: u64,
+
+    // Give up on startup if the Kafka brokers cannot be reached and the
+    // consumer set up within this time.  Zero waits indefinitely.
+    #[arg(long, env = "DKE_KAFKA_CONNECTION_TIMEOUT_SECONDS",
+          value_name = "SECONDS", default_value_t = 30)]
+    kafka_connection_timeout_seconds: u64,
+
+    // Where to start consuming on startup, as offsets are not committed.
+    #[arg(long, env = "DKE_FALLBACK_OFFSET", value_enum, default_value = "latest")]
+    fallback_offset: FallbackOffset,
+
+    // Drop messages larger than this, before or after decompression,
+    // without processing them.  Zero disables the limit.
+    #[arg(long, env = "DKE_MAX_MESSAGE_BYTES", value_name = "BYTES", default_value_t = 16 << 20)]
+    max_message_bytes: usize,
+
+    // Decompress message values which the producer has compressed.
+    #[arg(long, env = "DKE_MESSAGE_COMPRESSION", value_enum, default_value = "none")]
+    message_compression: collector::MessageCompression,
+
+    // The unit of queuing times, transfer times, and session durations in
+    // the records.  dCache uses milliseconds, so this only needs changing
+    // for producers which convert the records.
+    #[arg(long, env = "DKE_DURATION_UNIT", value_enum, default_value = "ms")]
+    duration_unit: collector::DurationUnit,
+
+    // Append records which cannot be parsed to this file, one JSON object
+    // per line with the time, the error, and the payload.
+    #[arg(long, env = "DKE_DEADLETTER_FILE", value_name = "PATH")]
+    deadletter_file: Option<std::path::PathBuf>,
+
+    // When the dead-letter file would grow beyond this size, move it aside
+    // with the suffix ".1", replacing the previous one, and start afresh.
+    #[arg(long, env = "DKE_DEADLETTER_MAX_BYTES",
+          value_name = "BYTES", default_value_t = 100_000_000)]
+    deadletter_max_bytes: u64,
+
+    // Save the counter values to this file on SIGTERM or SIGINT, and add
+    // the saved values back on startup, so that totals survive restarts.
+    // Series whose label names have changed since they were saved are
+    // dropped.  Counts are still lost or duplicated for records consumed
+    // around an unclean restart.
+    #[arg(long, env = "DKE_STATE_FILE", value_name = "PATH")]
+    state_file: Option<std::path::PathBuf>,
+
+    // How long to wait on shutdown for the queued messages to be processed
+    // before saving the state file and exiting anyway, so that a stuck
+    // worker cannot hold up a restart.  Zero waits indefinitely.
+    #[arg(long, env = "DKE_SHUTDOWN_TIMEOUT_SECONDS",
+          value_name = "SECONDS", default_value_t = 30)]
+    shutdown_timeout_seconds: u64,
+
+    // Read error messages, one per line, from this file or from standard
+    // input if "-", print each with its simplified form, and exit.  This
+    // shows how error labels are derived from status messages.
+    #[arg(long, env = "DKE_SIMPLIFY", value_name = "PATH")]
+    simplify: Option<std::path::PathBuf>,
+
+    // Simplify error messages with the rules in this file before the
+    // built-in ones.  Each line holds a regular expression and its
+    // replacement separated by a tab.  The file is reloaded on SIGHUP, and
+    // the previous rules are kept if it is invalid.
+    #[arg(long, env = "DKE_SIMPLIFIER_RULES", value_name = "FILE")]
+    simplifier_rules: Option<std::path::PathBuf>,
+
+    // Keep this many of the most recent messages and serve them at
+    // /debug/messages, one JSON object per line.  Zero disables this.
+    #[arg(long, env = "DKE_DEBUG_BUFFER_SIZE", value_name = "COUNT", default_value_t = 0)]
+    debug_buffer_size: usize,
+
+    // Print the names, types, help texts, and label names of the metrics
+    // which would be exported with the given options as JSON, then exit.
+    #[arg(long, env = "DKE_PRINT_METRICS_SCHEMA")]
+    print_metrics_schema: bool,
+
+    // Stop after processing this many records and print the metrics to
+    // standard output, instead of running indefinitely.
+    #[arg(long, env = "DKE_MAX_MESSAGES", value_name = "COUNT")]
+    max_messages: Option<u64>,
+
+    #[arg(long, env = "DKE_METRIC_PREFIX", default_value = "dcache_kafka_")]
     metric_prefix: String,
 
-    #[arg(long, default_value = "127.0.0.1:19997")]
-    listen: String,
+    // Rename metrics according to this file, where each line holds the
+    // default name of a metric, including the prefix, and the name to use
+    // instead separated by whitespace.
+    #[arg(long, env = "DKE_METRIC_NAME_MAP", value_name = "FILE")]
+    metric_name_map: Option<std::path::PathBuf>,
+
+    // The address to serve metrics on, either an IPv4 address and port like
+    // 127.0.0.1:19997, an IPv6 address and port like [::1]:19997, or, if
+    // built with the unix-socket feature, a socket path like
+    // unix:/run/dcache-kafka-exporter.sock.
+    #[arg(long, env = "DKE_LISTEN", default_value = "127.0.0.1:19997", value_parser = parse_listen)]
+    listen: server::ListenAddr,
+
+    // The path to serve metrics at, for running behind a reverse proxy which
+    // shares the host between several services.
+    #[arg(long, env = "DKE_METRICS_PATH", value_name = "PATH",
+          default_value = "/metrics", value_parser = parse_metrics_path)]
+    metrics_path: String,
+
+    // Serve metrics over HTTPS using the given certificate and private key.
+    #[arg(long, env = "DKE_METRICS_TLS_CERT",
+          value_name = "PEM-FILE", requires = "metrics_tls_key")]
+    metrics_tls_cert: Option<std::path::PathBuf>,
+
+    #[arg(long, env = "DKE_METRICS_TLS_KEY",
+          value_name = "PEM-FILE", requires = "metrics_tls_cert")]
+    metrics_tls_key: Option<std::path::PathBuf>,
+
+    // A file containing a token which scrapes must present as an
+    // "Authorization: Bearer" header.
+    #[arg(long, env = "DKE_METRICS_AUTH_TOKEN_FILE", value_name = "FILE")]
+    metrics_auth_token_file: Option<std::path::PathBuf>,
+
+    // Also push the metrics to this Prometheus remote-write endpoint, given
+    // as an http or https URL, for sites which do not allow scraping.
+    #[arg(long, env = "DKE_REMOTE_WRITE_URL", value_name = "URL",
+          value_parser = remote_write::Endpoint::parse)]
+    remote_write_url: Option<remote_write::Endpoint>,
+
+    // How often to push metrics to the remote-write endpoint.
+    #[arg(long, env = "DKE_REMOTE_WRITE_INTERVAL_SECONDS",
+          value_name = "SECONDS", default_value_t = 60)]
+    remote_write_interval_seconds: u64,
+
+    // Do not serve metrics over HTTP, only push them with --remote-write-url.
+    #[arg(long, env = "DKE_DISABLE_PULL", requires = "remote_write_url")]
+    disable_pull: bool,
+
+    // The number of messages which can be queued between the Kafka consumer
+    // and the processing thread before the consumer blocks.
+    #[arg(long, env = "DKE_QUEUE_CAPACITY", value_name = "COUNT", default_value_t = 1000)]
+    queue_capacity: usize,
+
+    // The number of threads processing messages.
+    #[arg(long, env = "DKE_WORKERS", value_name = "COUNT", default_value_t = 1)]
+    workers: usize,
+
+    // Message types which are known but not handled, and thus should not be
+    // counted as unparsed.
+    #[arg(long = "ignore-msg-type", env = "DKE_IGNORE_MSG_TYPE",
+          value_name = "MSG-TYPE", value_delimiter = ',',
+          default_values_t = ["hit".to_string(), "warning".to_string()])]
+    ignored_msg_types: Vec<String>,
+
+    // Only count events from cell domains fully matching one of these
+    // regular expressions.  May be repeated.
+    #[arg(long = "include-cell-domain", env = "DKE_INCLUDE_CELL_DOMAIN",
+          value_name = "REGEX", value_parser = parse_full_regex)]
+    include_cell_domains: Vec<Regex>,
+
+    // Skip events from cell domains fully matching any of these regular
+    // expressions, even if included.  May be repeated.
+    #[arg(long = "exclude-cell-domain", env = "DKE_EXCLUDE_CELL_DOMAIN",
+          value_name = "REGEX", value_parser = parse_full_regex)]
+    exclude_cell_domains: Vec<Regex>,
+
+    // Collapse cell names in the cell_name label, given as REGEX=REPLACEMENT
+    // where the regular expression must match the whole name and the
+    // replacement may refer to capture groups as $1 or ${name}.  The regular
+    // expression ends at the first "=".  May be repeated, in which case the
+    // first matching rewrite applies.
+    #[arg(long = "cell-name-rewrite", env = "DKE_CELL_NAME_REWRITE",
+          value_name = "REGEX=REPLACEMENT", value_parser = parse_cell_name_rewrite)]
+    cell_name_rewrites: Vec<(Regex, String)>,
+
+    // The maximum number of parse warnings to log per minute.
+    #[arg(long, env = "DKE_LOG_WARN_RATE", value_name = "COUNT", default_value_t = 10)]
+    log_warn_rate: u32,
+
+    // Whether to log plain text or one JSON object per line.
+    #[arg(long, env = "DKE_LOG_FORMAT", value_enum, default_value = "text")]
+    log_format: logging::LogFormat,
+
+    // Adds histograms of file sizes, which multiplies the number of series.
+    #[arg(long, env = "DKE_ENABLE_SIZE_HISTOGRAM")]
+    enable_size_histogram: bool,
+
+    // Adds a count of transfers by protocol and protocol version.  This adds
+    // a series for each version seen, but is independent of the other
+    // labels.
+    #[arg(long, env = "DKE_ENABLE_VERSION_METRIC")]
+    enable_version_metric: bool,
+
+    // Adds a count of requests by the types of principals in their subject,
+    // classified as dn, kerberos, token, uid, or other, to show which kinds
+    // of authentication are in use.  No identities are exposed.
+    #[arg(long, env = "DKE_ENABLE_SUBJECT_TYPE_METRIC")]
+    enable_subject_type_metric: bool,
+
+    // Whether to represent durations as histograms or summaries.
+    #[arg(long, env = "DKE_METRIC_STYLE", value_enum, default_value = "histogram")]
+    metric_style: collector::MetricStyle,
+
+    // The quantiles to export when using summaries.
+    #[arg(long, env = "DKE_SUMMARY_OBJECTIVES", value_name = "QUANTILES", value_delimiter = ',',
+          default_values_t = [0.5, 0.9, 0.99])]
+    summary_objectives: Vec<f64>,
+
+    // Metrics or kinds of metrics to leave out.
+    #[arg(long = "disable-metric", env = "DKE_DISABLE_METRIC",
+          value_name = "NAME", value_delimiter = ',',
+          value_parser = clap::builder::PossibleValuesParser::new(collector::METRIC_FAMILIES))]
+    disabled_metrics: Vec<String>,
+
+    // Mask the host part of IP addresses before they are logged.
+    #[arg(long, env = "DKE_ANONYMIZE_CLIENT_IP")]
+    anonymize_client_ip: bool,
+
+    // Bucket boundaries overriding the defaults for long durations, like
+    // transfer and queuing times, in seconds.
+    #[arg(long, env = "DKE_LONG_DURATION_BUCKETS", value_name = "SECONDS", value_delimiter = ',')]
+    long_duration_buckets: Vec<f64>,
+
+    // Bucket boundaries overriding the defaults for short durations, like
+    // request sessions, in seconds.
+    #[arg(long, env = "DKE_SHORT_DURATION_BUCKETS", value_name = "SECONDS", value_delimiter = ',')]
+    short_duration_buckets: Vec<f64>,
+
+    // Bucket boundaries overriding the defaults for the mean transfer
+    // bandwidths, in bytes per second.
+    #[arg(long, env = "DKE_TRANSFER_RATE_BUCKETS",
+          value_name = "BYTES-PER-SECOND", value_delimiter = ',')]
+    transfer_rate_buckets: Vec<f64>,
+
+    // The fraction of events for which to observe the *_seconds histograms
+    // or summaries, to save CPU at high event rates.  Counters are always
+    // exact, but histograms then only represent a random sample.
+    #[arg(long, env = "DKE_HISTOGRAM_SAMPLE_RATE", value_name = "RATE", default_value_t = 1.0,
+          value_parser = parse_sample_rate)]
+    histogram_sample_rate: f64,
+
+    // Skip remove, restore, and store records whose transaction ID is among
+    // this many recently seen, as happens when dCache re-emits records.
+    // Skipped restores and stores are also counted as tape retries.  Zero
+    // disables the check.
+    #[arg(long, env = "DKE_DEDUP_WINDOW", value_name = "COUNT", default_value_t = 0)]
+    dedup_window: usize,
+
+    // Remove label combinations which have not been seen for this long.
+    // This resets their counters if they reappear.
+    #[arg(long, env = "DKE_LABEL_TTL_SECONDS", value_name = "SECONDS")]
+    label_ttl_seconds: Option<u64>,
+
+    // Count events under label values of "<overflow>" once this many label
+    // combinations have been seen, summed over event kinds, failure counts
+    // and the maximum queuing times, to protect the monitoring backend from
+    // a flood of new series.  Combinations expired by --label-ttl-seconds or
+    // reset with the queuing gauge window are no longer counted.
+    #[arg(long, env = "DKE_MAX_SERIES", value_name = "COUNT")]
+    max_series: Option<usize>,
+
+    // Truncate label values longer than this many characters, marking the
+    // cut with "…", to keep pathological values out of the exposition.
+    // Zero disables the limit.
+    #[arg(long, env = "DKE_MAX_LABEL_LENGTH", value_name = "CHARS", default_value_t = 256)]
+    max_label_length: usize,
+
+    // How often to look for label combinations to expire.
+    #[arg(long, env = "DKE_LABEL_SWEEP_INTERVAL_SECONDS",
+          value_name = "SECONDS", default_value_t = 60)]
+    label_sweep_interval_seconds: u64,
+
+    // Expose the maximum queuing time seen per cell, starting over at this
+    // interval, to show where requests are currently waiting.
+    #[arg(long, env = "DKE_QUEUING_GAUGE_WINDOW_SECONDS", value_name = "SECONDS")]
+    queuing_gauge_window_seconds: Option<u64>,
+
+    // Expose an estimate of the number of distinct PNFS IDs seen, starting
+    // over at this interval.  The estimate is based on a HyperLogLog sketch
+    // of 16 KiB, which is accurate to about one percent.
+    #[arg(long, env = "DKE_UNIQUE_WINDOW_SECONDS", value_name = "SECONDS")]
+    unique_window_seconds: Option<u64>,
+
+    // Attach the PNFS ID of the latest observation to each bucket of the
+    // duration histograms of transfers, restores, and stores.  Exemplars are
+    // only shown in the OpenMetrics format.
+    #[arg(long, env = "DKE_ENABLE_EXEMPLARS")]
+    enable_exemplars: bool,
+
+    // Expose moving averages of the bytes transferred, restored, and stored
+    // per second, for displays which cannot compute rates from counters.
+    // The averages have a time constant of one minute.  The byte counters
+    // remain the accurate source for such rates.
+    #[arg(long, env = "DKE_ENABLE_RATE_GAUGES")]
+    enable_rate_gauges: bool,
+
+    // Expose the CPU time, memory use, and file descriptors of the exporter
+    // itself as the standard process_* metrics.  These are read from /proc,
+    // so this is only enabled by default on Linux.
+    #[arg(long, env = "DKE_ENABLE_PROCESS_METRICS", value_name = "BOOL",
+          default_value_t = cfg!(target_os = "linux"), num_args = 0..=1,
+          default_missing_value = "true", action = ArgAction::Set)]
+    enable_process_metrics: bool,
+
+    // Label remove, request, restore, and store metrics by a category of the
+    // status code, like "ok", "timeout", or "no-such-file", or "other" for
+    // unknown codes.
+    #[arg(long, env = "DKE_ENABLE_STATUS_CATEGORY")]
+    enable_status_category: bool,
+
+    // Add or override status categories from this file, where each line
+    // holds a status code and a category separated by whitespace.
+    #[arg(long, env = "DKE_STATUS_CODE_MAP",
+          value_name = "FILE", requires = "enable_status_category")]
+    status_code_map: Option<std::path::PathBuf>,
+
+    // Label the failure counts of restores and stores by a class of the
+    // error, like "drive-busy", "media-error", "not-found", or "timeout",
+    // or "other" if no pattern matches.  The classes come from patterns
+    // matching the error messages of common HSM scripts.
+    #[arg(long, env = "DKE_ENABLE_HSM_ERROR_CLASS")]
+    enable_hsm_error_class: bool,
+
+    // Add HSM error classes from this file, where each line holds a regular
+    // expression and a class separated by a tab.  These are tried before the
+    // built-in patterns.
+    #[arg(long, env = "DKE_HSM_ERROR_MAP",
+          value_name = "FILE", requires = "enable_hsm_error_class")]
+    hsm_error_map: Option<std::path::PathBuf>,
+
+    // Add or override the categories of cell types for the cell_category
+    // label from this file, where each line holds a cell type and a category
+    // separated by whitespace.  Cell types which are neither built in nor
+    // given here are categorized as "other".
+    #[arg(long, env = "DKE_CELL_TYPE_MAP", value_name = "FILE")]
+    cell_type_map: Option<std::path::PathBuf>,
+
+    // Label request and transfer metrics by the VO of the primary FQAN of
+    // the subject, or "unknown" if there is none.
+    #[arg(long, env = "DKE_ENABLE_VO_LABEL")]
+    enable_vo_label: bool,
+
+    // Label transfer metrics by the host of the local endpoint of the pool,
+    // or the cell name if absent.  This multiplies the number of transfer
+    // series by the number of pools.
+    #[arg(long, env = "DKE_ENABLE_POOL_LABEL")]
+    enable_pool_label: bool,
+
+    // Label transfer metrics by the name of the cell which initiated the
+    // transfer, or "unknown" if it cannot be determined.
+    #[arg(long, env = "DKE_ENABLE_INITIATOR_LABEL")]
+    enable_initiator_label: bool,
+
+    // Log a summary of the records processed every this many seconds.  Zero
+    // disables the summary.
+    #[arg(long, env = "DKE_STATS_INTERVAL_SECONDS", value_name = "SECONDS",
+          default_value_t = 60)]
+    stats_interval_seconds: u64,
+
+    // Label all event metrics with the Kafka message key under this label
+    // name.  Keys which are absent or not UTF-8 give an empty value.
+    #[arg(long, env = "DKE_KEY_LABEL", value_name = "NAME", value_parser = parse_label_name)]
+    key_label: Option<String>,
+
+    // Leave these labels out of the metrics of events, to reduce the number
+    // of series where a label is of no interest.
+    #[arg(long, env = "DKE_DROP_LABELS", value_name = "NAMES", value_delimiter = ',',
+          value_parser = parse_drop_label)]
+    drop_labels: Vec<String>,
+}
+
+fn parse_sample_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => { Ok(rate) }
+        _ => { Err("expected a number from 0 to 1".to_string()) }
+    }
+}
+
+// Compiles a regular expression which must match the whole string.
+fn parse_full_regex(s: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", s))
+}
+
+fn parse_cell_name_rewrite(s: &str) -> Result<(Regex, String), String> {
+    let (re, replacement) = s.split_once('=')
+        .ok_or_else(|| "expected REGEX=REPLACEMENT".to_string())?;
+    let re = parse_full_regex(re).map_err(|err| err.to_string())?;
+    Ok((re, replacement.to_string()))
+}
+
+fn parse_drop_label(s: &str) -> Result<String, String> {
+    if !collector::is_droppable_label(s) {
+        return Err("not a label which can be dropped".to_string());
+    }
+    Ok(s.to_string())
+}
+
+fn parse_label_name(s: &str) -> Result<String, String> {
+    let mut chars = s.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') &&
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_') && !s.starts_with("__");
+    if !valid {
+        return Err("not a valid Prometheus label name".to_string());
+    }
+    if collector::is_event_label(s) {
+        return Err("already used by the exporter".to_string());
+    }
+    Ok(s.to_string())
+}
+
+fn parse_listen(s: &str) -> Result<server::ListenAddr, String> {
+    #[cfg(feature = "unix-socket")]
+    if let Some(path) = s.strip_prefix("unix:") {
+        return Ok(server::ListenAddr::Unix(path.into()));
+    }
+    match s.parse::<SocketAddr>() {
+        Ok(addr) => { Ok(server::ListenAddr::Tcp(addr)) }
+        Err(_) if s.parse::<IpAddr>().is_ok() => { Err("missing port".to_string()) }
+        Err(err) => { Err(err.to_string()) }
+    }
+}
+
+fn parse_metrics_path(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') {
+        return Err("the path must start with a slash".to_string());
+    }
+    Ok(s.to_string())
+}
+
+// Fills in the TLS options not given from the files found in --secrets-dir.
+fn apply_secrets_dir(args: &mut Args) {
+    let Some(dir) = &args.secrets_dir else {
+        return;
+    };
+    if !dir.is_dir() {
+        eprintln!("error: --secrets-dir {} is not a directory", dir.display());
+        std::process::exit(1);
+    }
+    let existing = |name: &str| Some(dir.join(name)).filter(|path| path.is_file());
+    if args.ca_path.is_none() {
+        args.ca_path = existing("ca.pem");
+    }
+    if args.cert_path.is_none() && args.key_path.is_none() && args.pkcs12_path.is_none() {
+        if let (Some(cert_path), Some(key_path)) = (existing("tls.crt"), existing("tls.key")) {
+            args.cert_path = Some(cert_path);
+            args.key_path = Some(key_path);
+        }
+    }
+}
+
+fn kafka_ssl_connector(args: &Args) -> Result<ssl::SslConnector, String> {
+    let mut builder = ssl::SslConnector::builder(ssl::SslMethod::tls_client())
+        .map_err(|err| format!("cannot create TLS context: {}", err))?;
+    if let Some(p) = &args.cert_path {
+        builder.set_certificate_file(p, ssl::SslFiletype::PEM)
+            .map_err(|err| format!("cannot load --client-cert {}: {}", p.display(), err))?;
+    }
+    if let Some(p) = &args.key_path {
+        builder.set_private_key_file(p, ssl::SslFiletype::PEM)
+            .map_err(|err| format!("cannot load --client-key {}: {}", p.display(), err))?;
+        builder.check_private_key()
+            .map_err(|err| format!("--client-key does not match --client-cert: {}", err))?;
+    }
+    if let Some(p) = &args.pkcs12_path {
+        let password = match &args.pkcs12_password_path {
+            Some(pp) => {
+                std::fs::read_to_string(pp)
+                    .map_err(|err| format!("cannot read --client-pkcs12-password-file {}: {}",
+                                           pp.display(), err))?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string()
+            }
+            None => { String::new() }
+        };
+        let der = std::fs::read(p)
+            .map_err(|err| format!("cannot read --client-pkcs12 {}: {}", p.display(), err))?;
+        let identity = Pkcs12::from_der(&der)
+            .and_then(|pkcs12| pkcs12.parse2(&password))
+            .map_err(|err| format!("cannot load --client-pkcs12 {}: {}", p.display(), err))?;
+        let (Some(cert), Some(pkey)) = (identity.cert, identity.pkey) else {
+            return Err(format!("--client-pkcs12 {} lacks a certificate or key", p.display()));
+        };
+        builder.set_certificate(&cert)
+            .and_then(|()| builder.set_private_key(&pkey))
+            .map_err(|err| format!("cannot use --client-pkcs12 {}: {}", p.display(), err))?;
+        for ca_cert in identity.ca.into_iter().flatten() {
+            builder.add_extra_chain_cert(ca_cert)
+                .map_err(|err| format!("cannot use --client-pkcs12 {}: {}", p.display(), err))?;
+        }
+        builder.check_private_key()
+            .map_err(|err| format!("--client-pkcs12 key does not match its certificate: {}",
+                                   err))?;
+    }
+    if let Some(p) = &args.ca_path {
+        let is_dir = std::fs::metadata(p)
+            .map_err(|err| format!("cannot access --kafka-ca {}: {}", p.display(), err))?
+            .is_dir();
+        let result = if is_dir {
+            builder.load_verify_locations(None, Some(p))
+        } else {
+            builder.set_ca_file(p)
+        };
+        result.map_err(|err| format!("cannot load --kafka-ca {}: {}", p.display(), err))?;
+    }
+    Ok(builder.build())
+}
+
+// Whether an option holds a secret, going by its whole name or its last word.
+// Options merely mentioning secrets, like --secrets-dir or
+// --client-pkcs12-password-file, name files and do not reveal their contents.
+fn is_secret_option(name: &str) -> bool {
+    const SECRET_WORDS : &[&str] = &["password", "secret", "token"];
+    let last_word = name.rsplit('-').next().unwrap_or(name);
+    SECRET_WORDS.contains(&last_word)
+}
+
+// The options in effect and where they came from, with the values of options
+// holding secrets redacted.
+fn effective_config(matches: &ArgMatches) -> serde_json::Value {
+    let mut config = serde_json::Map::new();
+    for arg in Args::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        let (Some(values), Some(source)) = (matches.get_raw(id), matches.value_source(id)) else {
+            continue;
+        };
+        let name = arg.get_long().unwrap_or(id);
+        let is_secret = is_secret_option(name);
+        let values: Vec<String> = values
+            .map(|value| {
+                if is_secret {
+                    "<redacted>".to_string()
+                } else {
+                    value.to_string_lossy().into_owned()
+                }
+            })
+            .collect();
+        let value = match arg.get_action() {
+            ArgAction::Append => { serde_json::json!(values) }
+            _ => { serde_json::json!(values.join(",")) }
+        };
+        let source = match source {
+            ValueSource::DefaultValue => { "default" }
+            ValueSource::EnvVariable => { "environment" }
+            _ => { "command line" }
+        };
+        config.insert(name.to_string(), serde_json::json!({"value": value, "source": source}));
+    }
+    serde_json::Value::Object(config)
+}
+
+// How long to wait for a TCP connection when checking bootstrap hosts.
+const BOOTSTRAP_PROBE_TIMEOUT : Duration = Duration::from_secs(5);
+
+// How often to report log messages suppressed by throttles.
+const THROTTLE_FLUSH_INTERVAL : Duration = Duration::from_secs(5);
+
+// How often to refresh the estimate of distinct PNFS IDs within a window.
+const UNIQUE_ESTIMATE_INTERVAL : Duration = Duration::from_secs(15);
+
+static SHUTDOWN_REQUESTED : AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+// Makes SIGTERM and SIGINT stop the consumer loop instead of terminating the
+// process.
+fn handle_shutdown_signals() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+static RELOAD_REQUESTED : AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+// Makes SIGHUP request a reload of the configuration files instead of
+// terminating the process.
+fn handle_reload_signal() {
+    let handler = request_reload as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGHUP, handler);
+    }
+}
+
+thread_local! {
+    // Set while processing a message, when panics are caught and logged with
+    // a throttle instead of by the panic hook, which records the location of
+    // the panic for that log entry.
+    static CATCHING_PANICS : Cell<bool> = const { Cell::new(false) };
+    static PANIC_LOCATION : RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// Keeps the panic hook quiet about panics caught while processing messages,
+// which a flood of bad messages could otherwise turn into a flood of logs.
+fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if CATCHING_PANICS.get() {
+            PANIC_LOCATION.set(info.location().map_or(String::new(), ToString::to_string));
+        } else {
+            default_hook(info);
+        }
+    }));
+}
+
+// The requested partitions which exist in the loaded metadata, warning about
+// the others.  An empty request stands for all partitions.
+fn existing_partitions(client: &KafkaClient, topic: &str, requested: &[i32])
+    -> Result<Vec<i32>, String>
+{
+    let topics = client.topics();
+    let Some(topic_partitions) = topics.partitions(topic) else {
+        // Leave it to the consumer to report the missing topic.
+        return Ok(requested.to_vec());
+    };
+    let mut partitions = vec![];
+    for &id in requested {
+        if topic_partitions.partition(id).is_some() {
+            partitions.push(id);
+        } else {
+            warn!(topic, partition = id; "Ignoring a partition which does not exist.");
+        }
+    }
+    if partitions.is_empty() && !requested.is_empty() {
+        return Err(format!("none of the partitions {:?} of {} exist", requested, topic));
+    }
+    Ok(partitions)
+}
+
+// Whether a TCP connection to the host can be established.
+fn is_reachable(host: &str, timeout: Duration) -> bool {
+    let Ok(addrs) = host.to_socket_addrs() else {
+        return false;
+    };
+    addrs.into_iter().any(|addr| TcpStream::connect_timeout(&addr, timeout).is_ok())
+}
+
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"].iter()
+        .find_map(|p| std::fs::read_to_string(p).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| "localhost".to_string())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-    env_logger::init();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    apply_secrets_dir(&mut args);
+    logging::init(args.log_format);
+
+    let message_rewrite_rules = match &args.simplifier_rules {
+        Some(path) => {
+            MessageRewriteRules::load(path).unwrap_or_else(|err| {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            })
+        }
+        None => { MessageRewriteRules::default() }
+    };
+
+    if let Some(path) = &args.simplify {
+        let input = if path.as_os_str() == "-" {
+            std::io::read_to_string(std::io::stdin())?
+        } else {
+            std::fs::read_to_string(path)?
+        };
+        let rules = message_rewrite_rules;
+        for line in input.lines() {
+            println!("{} => {}", line, rules.rewrite(line));
+        }
+        return Ok(());
+    }
+
+    for buckets in [&args.long_duration_buckets, &args.short_duration_buckets,
+                    &args.transfer_rate_buckets] {
+        if !buckets.windows(2).all(|w| w[0] < w[1]) {
+            return Err("Bucket boundaries must be strictly increasing.".into());
+        }
+    }
+
+    // Set up before moving out of args, but only fail once it is needed.
+    let ssl_connector = kafka_ssl_connector(&args);
+
+    let mut metric_names = MetricNames::new(args.metric_prefix.clone());
+    if let Some(path) = &args.metric_name_map {
+        if let Err(err) = metric_names.load(path) {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    // The Kafka client does not expose the state of its connections to the
+    // individual brokers, so this covers the cluster as a whole.
+    let kafka_connected = register_int_gauge!(
+        metric_names.get("kafka_connected"),
+        "Whether the last requests to the Kafka cluster succeeded.")?;
+    let set_connected = |connected: bool| kafka_connected.set(connected as i64);
+    set_connected(false);
+    let kafka_bootstrap_unreachable = register_int_gauge_vec!(
+        metric_names.get("kafka_bootstrap_unreachable"),
+        "Whether a bootstrap host could not be reached at startup.",
+        &["host"])?;
+
+    let remote_write_errors = if args.remote_write_url.is_some() {
+        Some(register_int_counter!(
+            metric_names.get("remote_write_error_count"),
+            "The number of failed attempts to push metrics to the remote-write endpoint.")?)
+    } else {
+        None
+    };
+    let metadata_refresh = if args.metadata_refresh_seconds > 0 {
+        let errors = register_int_counter!(
+            metric_names.get("kafka_metadata_error_count"),
+            "The number of failed attempts to reload the Kafka metadata.")?;
+        let interval = Duration::from_secs(args.metadata_refresh_seconds);
+        Some(MetadataRefresh::new(interval, errors))
+    } else {
+        None
+    };
+    let process_collector = if args.enable_process_metrics {
+        let process_collector = ProcessCollector::new()?;
+        default_registry().register(Box::new(process_collector.clone()))?;
+        Some(process_collector)
+    } else {
+        None
+    };
+    let queue_depth = register_int_gauge!(
+        metric_names.get("queue_depth"),
+        "The number of messages waiting to be processed.")?;
+    let process_panic_count = register_int_counter!(
+        metric_names.get("process_panic_count"),
+        "The number of messages whose processing panicked and was skipped.")?;
+    let panic_error_throttle = Arc::new(LogThrottle::new(
+        "panic errors", args.log_warn_rate, Duration::from_secs(60)));
+    let deadletter = match args.deadletter_file {
+        Some(path) => {
+            match DeadLetterFile::open(path.clone(), args.deadletter_max_bytes) {
+                Ok(deadletter) => { Some(deadletter) }
+                Err(err) => {
+                    eprintln!("error: cannot open {}: {}", path.display(), err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => { None }
+    };
+    let status_categories = if args.enable_status_category {
+        let mut status_categories = StatusCategories::builtin();
+        if let Some(path) = &args.status_code_map {
+            if let Err(err) = status_categories.load(path) {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Some(status_categories)
+    } else {
+        None
+    };
+    let hsm_error_classes = if args.enable_hsm_error_class {
+        let mut hsm_error_classes = HsmErrorClasses::builtin();
+        if let Some(path) = &args.hsm_error_map {
+            if let Err(err) = hsm_error_classes.load(path) {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Some(hsm_error_classes)
+    } else {
+        None
+    };
+    let mut cell_categories = CellCategories::builtin();
+    if let Some(path) = &args.cell_type_map {
+        if let Err(err) = cell_categories.load(path) {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    }
+    let debug_messages = (args.debug_buffer_size > 0)
+        .then(|| Arc::new(RecentMessages::new(args.debug_buffer_size)));
+    let collector = collector::Collector::new(collector::Config {
+        metric_names,
+        ignored_msg_types: args.ignored_msg_types,
+        include_cell_domains: args.include_cell_domains,
+        exclude_cell_domains: args.exclude_cell_domains,
+        cell_name_rewrites: args.cell_name_rewrites,
+        message_rewrite_rules,
+        log_warn_rate: args.log_warn_rate,
+        enable_size_histogram: args.enable_size_histogram,
+        enable_version_metric: args.enable_version_metric,
+        enable_subject_type_metric: args.enable_subject_type_metric,
+        metric_style: args.metric_style,
+        summary_objectives: args.summary_objectives,
+        disabled_metrics: args.disabled_metrics,
+        anonymize_client_ip: args.anonymize_client_ip,
+        enable_vo_label: args.enable_vo_label,
+        enable_pool_label: args.enable_pool_label,
+        enable_initiator_label: args.enable_initiator_label,
+        key_label: args.key_label.clone(),
+        drop_labels: args.drop_labels,
+        enable_exemplars: args.enable_exemplars,
+        enable_rate_gauges: args.enable_rate_gauges,
+        dedup_window: args.dedup_window,
+        label_ttl: args.label_ttl_seconds.map(Duration::from_secs),
+        enable_max_recent_queuing: args.queuing_gauge_window_seconds.is_some(),
+        enable_unique_pnfsid_estimate: args.unique_window_seconds.is_some(),
+        histogram_sample_rate: args.histogram_sample_rate,
+        max_series: args.max_series,
+        max_label_length: args.max_label_length,
+        status_categories,
+        cell_categories,
+        hsm_error_classes,
+        message_compression: args.message_compression,
+        max_message_bytes: args.max_message_bytes,
+        duration_unit: args.duration_unit,
+        deadletter,
+        debug_messages: debug_messages.clone(),
+        long_duration_buckets: args.long_duration_buckets,
+        short_duration_buckets: args.short_duration_buckets,
+        transfer_rate_buckets: args.transfer_rate_buckets,
+    }, default_registry());
+    let collector = match collector {
+        Ok(collector) => { collector }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if args.print_metrics_schema {
+        let mut schema = collector.schema();
+        for (metric, type_) in [
+            (&kafka_connected as &dyn core::Collector, "gauge"),
+            (&kafka_bootstrap_unreachable, "gauge"),
+            (&queue_depth, "gauge"),
+            (&process_panic_count, "counter"),
+        ] {
+            schema.extend(schema::describe(metric, type_));
+        }
+        if let Some(metadata_refresh) = &metadata_refresh {
+            schema.extend(metadata_refresh.schema());
+        }
+        if let Some(remote_write_errors) = &remote_write_errors {
+            schema.extend(schema::describe(remote_write_errors, "counter"));
+        }
+        if let Some(process_collector) = &process_collector {
+            schema.extend(process_collector.schema());
+        }
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
 
-    let mut builder = ssl::SslConnector::builder(ssl::SslMethod::tls_client())?;
-    if let Some(p) = args.cert_path {
-        builder.set_certificate_file(p, ssl::SslFiletype::PEM)?;
+    if let Some(path) = &args.state_file {
+        let samples = match state::load(path) {
+            Ok(samples) => { samples }
+            Err(err) => {
+                eprintln!("error: cannot load {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        };
+        let dropped = state::restore(&collector.counters(), &samples);
+        if dropped > 0 {
+            warn!(path:% = path.display(), dropped;
+                  "Dropped saved counter values which no longer match the metrics.");
+        }
+        handle_shutdown_signals();
     }
-    if let Some(p) = args.key_path {
-        builder.set_private_key_file(p, ssl::SslFiletype::PEM)?;
+
+    // Serve metrics before connecting to Kafka, so that a busy port is
+    // reported at once.
+    let metrics_ssl = match (args.metrics_tls_cert, args.metrics_tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            Some(tiny_http::SslConfig {
+                certificate: std::fs::read(cert_path)?,
+                private_key: std::fs::read(key_path)?,
+            })
+        }
+        _ => { None }
+    };
+    let metrics_auth_token = match args.metrics_auth_token_file {
+        Some(path) => {
+            let token = std::fs::read_to_string(&path)?.trim().to_string();
+            if token.is_empty() {
+                return Err(format!("The token file {} is empty.", path.display()).into());
+            }
+            Some(token)
+        }
+        None => { None }
+    };
+    let server_config = server::Config {
+        listen: args.listen.clone(),
+        metrics_path: args.metrics_path.clone(),
+        ssl: metrics_ssl,
+        auth_token: metrics_auth_token,
+        debug_messages,
+        effective_config: serde_json::to_vec_pretty(&effective_config(&matches))?,
+    };
+    if args.disable_pull {
+        info!("Not serving metrics, as requested by --disable-pull.");
+    } else if let Err(err) = server::start(server_config) {
+        eprintln!("error: cannot serve metrics on {}: {}", args.listen, err);
+        std::process::exit(1);
+    }
+    if let (Some(endpoint), Some(errors)) = (args.remote_write_url.clone(), remote_write_errors) {
+        let interval = Duration::from_secs(args.remote_write_interval_seconds.max(1));
+        remote_write::start(endpoint, interval, errors);
     }
-    if let Some(p) = args.ca_path {
-        builder.set_ca_file(p)?;
+
+    let ssl_connector = match ssl_connector {
+        Ok(ssl_connector) => { ssl_connector }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let kafka_client_id = args.kafka_client_id
+        .unwrap_or_else(|| format!("dcache-kafka-exporter@{}", hostname()));
+
+    // The client tries the bootstrap hosts in order, so put the reachable
+    // ones first rather than waiting for the others to time out.
+    let reachable: Vec<bool> = thread::scope(|scope| {
+        let probes: Vec<_> = args.kafka_hosts.iter()
+            .map(|host| scope.spawn(move || is_reachable(host, BOOTSTRAP_PROBE_TIMEOUT)))
+            .collect();
+        probes.into_iter().map(|probe| probe.join().unwrap()).collect()
+    });
+    let (mut bootstrap_hosts, mut unreachable_hosts) = (vec![], vec![]);
+    for (host, reachable) in args.kafka_hosts.iter().zip(reachable) {
+        kafka_bootstrap_unreachable.with_label_values(&[host]).set(!reachable as i64);
+        if reachable {
+            bootstrap_hosts.push(host.clone());
+        } else {
+            warn!(host; "Kafka bootstrap host is unreachable.");
+            unreachable_hosts.push(host.clone());
+        }
     }
-    let ssl_connector = builder.build();
+    bootstrap_hosts.append(&mut unreachable_hosts);
 
     let security_config = SecurityConfig::new(ssl_connector);
-    let mut kafka_client = KafkaClient::new_secure(args.kafka_hosts, security_config);
-        kafka_client.load_metadata_all().unwrap();
-    let mut kafka_consumer = Consumer::from_client(kafka_client)
-        .with_topic(args.kafka_topic)
-        .with_fallback_offset(FetchOffset::Latest)
-        .create()?;
-    let mut collector = collector::Collector::new(args.metric_prefix);
-    let _exporter = prometheus_exporter::start(args.listen.parse().unwrap());
-    loop {
-        for msgs in kafka_consumer.poll().unwrap().iter() {
-            for msg in msgs.messages() {
-                collector.process_message(str::from_utf8(msg.value)?);
+    let kafka_hosts = args.kafka_hosts.join(",");
+    let mut kafka_client = KafkaClient::new_secure(bootstrap_hosts, security_config);
+    let fallback_offset = match args.fallback_offset {
+        FallbackOffset::Earliest => { FetchOffset::Earliest }
+        FallbackOffset::Latest => { FetchOffset::Latest }
+    };
+    let (fetch_max_bytes, fetch_min_bytes) = (args.fetch_max_bytes, args.fetch_min_bytes);
+    let poll_max_wait = Duration::from_millis(args.poll_max_wait_ms);
+    let kafka_topic = args.kafka_topic;
+    let partitions = args.partitions;
+
+    // The Kafka client has no connect timeout, so connect on a separate
+    // thread and abandon it if it does not finish in time.
+    let (connected_tx, connected_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let result = kafka_client.load_metadata_all().map_err(|err| err.to_string()).and_then(|_| {
+            let partitions = existing_partitions(&kafka_client, &kafka_topic, &partitions)?;
+            Consumer::from_client(kafka_client)
+                .with_topic_partitions(kafka_topic, &partitions)
+                .with_fallback_offset(fallback_offset)
+                .with_fetch_max_bytes_per_partition(fetch_max_bytes)
+                .with_fetch_min_bytes(fetch_min_bytes)
+                .with_fetch_max_wait_time(poll_max_wait)
+                .with_client_id(kafka_client_id)
+                .create()
+                .map_err(|err| err.to_string())
+        });
+        let _ = connected_tx.send(result);
+    });
+    let connected = if args.kafka_connection_timeout_seconds > 0 {
+        let timeout = Duration::from_secs(args.kafka_connection_timeout_seconds);
+        connected_rx.recv_timeout(timeout).map_err(|_| {
+            format!("timed out after {:?}", timeout)
+        })
+    } else {
+        connected_rx.recv().map_err(|err| err.to_string())
+    };
+    let kafka_consumer = match connected.and_then(|result| result) {
+        Ok(kafka_consumer) => { kafka_consumer }
+        Err(err) => {
+            eprintln!("error: cannot connect to Kafka at {}: {}", kafka_hosts, err);
+            std::process::exit(1);
+        }
+    };
+    set_connected(true);
+    let collector = Arc::new(collector);
+    if let Some(path) = args.simplifier_rules.clone() {
+        let collector = Arc::clone(&collector);
+        handle_reload_signal();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(1));
+                if !RELOAD_REQUESTED.swap(false, Ordering::Relaxed) {
+                    continue;
+                }
+                match MessageRewriteRules::load(&path) {
+                    Ok(rules) => {
+                        info!(path:% = path.display(), rules = rules.len();
+                              "Reloaded the simplifier rules.");
+                        collector.set_message_rewrite_rules(rules);
+                    }
+                    Err(error) => {
+                        error!(error; "Failed to reload the simplifier rules, keeping the old.");
+                    }
+                }
+            }
+        });
+    }
+    // Report suppressed log messages without waiting for the next one.
+    {
+        let collector = Arc::clone(&collector);
+        let panic_error_throttle = Arc::clone(&panic_error_throttle);
+        thread::spawn(move || {
+            loop {
+                thread::sleep(THROTTLE_FLUSH_INTERVAL);
+                collector.flush_log_throttles();
+                panic_error_throttle.flush();
+            }
+        });
+    }
+    if args.label_ttl_seconds.is_some() {
+        let collector = Arc::clone(&collector);
+        let interval = Duration::from_secs(args.label_sweep_interval_seconds.max(1));
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                collector.expire_labels();
             }
+        });
+    }
+    if args.enable_rate_gauges {
+        let collector = Arc::clone(&collector);
+        thread::spawn(move || {
+            loop {
+                thread::sleep(rate::DECAY_INTERVAL);
+                collector.decay_rates();
+            }
+        });
+    }
+    if let Some(window_seconds) = args.queuing_gauge_window_seconds {
+        let collector = Arc::clone(&collector);
+        let interval = Duration::from_secs(window_seconds.max(1));
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                collector.reset_max_recent_queuing();
+            }
+        });
+    }
+    if let Some(window_seconds) = args.unique_window_seconds {
+        let collector = Arc::clone(&collector);
+        let window = Duration::from_secs(window_seconds.max(1));
+        thread::spawn(move || {
+            let mut window_start = Instant::now();
+            loop {
+                thread::sleep(UNIQUE_ESTIMATE_INTERVAL.min(window));
+                let reset = window_start.elapsed() >= window;
+                collector.update_unique_pnfsids(reset);
+                if reset {
+                    window_start = Instant::now();
+                }
+            }
+        });
+    }
+    if args.stats_interval_seconds > 0 {
+        let collector = Arc::clone(&collector);
+        let queue_depth = queue_depth.clone();
+        let interval = Duration::from_secs(args.stats_interval_seconds);
+        thread::spawn(move || {
+            let mut last = collector.totals();
+            loop {
+                thread::sleep(interval);
+                let totals = collector.totals();
+                let by_type: Vec<String> = totals.processed.iter()
+                    .map(|(msg_type, count)| {
+                        let last_count = last.processed.get(msg_type).copied().unwrap_or(0);
+                        format!("{}={}", msg_type, count.saturating_sub(last_count))
+                    })
+                    .collect();
+                let processed = totals.processed.values().sum::<u64>()
+                    .saturating_sub(last.processed.values().sum::<u64>());
+                let unparsed = totals.unparsed.saturating_sub(last.unparsed);
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                    .map(|t| t.as_secs_f64()).unwrap_or(0.0);
+                let idle_seconds = if totals.last_message_timestamp > 0.0 {
+                    (now - totals.last_message_timestamp).max(0.0).round() as u64
+                } else {
+                    0
+                };
+                info!(processed, by_type = by_type.join(" "), unparsed,
+                      queue_depth = queue_depth.get(), idle_seconds;
+                      "Processed {} records in the last {}s.", processed, interval.as_secs());
+                last = totals;
+            }
+        });
+    }
+
+    let (sender, receiver) =
+        mpsc::sync_channel::<(Vec<u8>, collector::Origin)>(args.queue_capacity);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let processed_count = Arc::new(AtomicU64::new(0));
+    let done = move |count: &AtomicU64| {
+        args.max_messages.is_some_and(|max| count.load(Ordering::Relaxed) >= max)
+    };
+    install_panic_hook();
+    let mut workers = vec![];
+    for _ in 0..args.workers.max(1) {
+        let receiver = Arc::clone(&receiver);
+        let collector = Arc::clone(&collector);
+        let processed_count = Arc::clone(&processed_count);
+        let queue_depth = queue_depth.clone();
+        let process_panic_count = process_panic_count.clone();
+        let panic_error_throttle = Arc::clone(&panic_error_throttle);
+        workers.push(thread::spawn(move || {
+            loop {
+                let received = receiver.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                let (payload, origin) = match received {
+                    Ok(record) => { record }
+                    Err(_) => { break }
+                };
+                queue_depth.dec();
+                if done(&processed_count) {
+                    continue;
+                }
+                // A bug triggered by one message should not take down the
+                // worker, so skip the message if processing panics.
+                CATCHING_PANICS.set(true);
+                let parsed = catch_unwind(AssertUnwindSafe(|| {
+                    collector.process_payload(&payload, &origin)
+                }));
+                CATCHING_PANICS.set(false);
+                match parsed {
+                    Ok(parsed) => {
+                        processed_count.fetch_add(parsed as u64, Ordering::Relaxed);
+                    }
+                    Err(panic) => {
+                        process_panic_count.inc();
+                        if panic_error_throttle.allow() {
+                            let reason = panic.downcast_ref::<&str>().copied()
+                                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                                .unwrap_or("unknown");
+                            let location = PANIC_LOCATION.take();
+                            error!(topic = &*origin.topic, partition = origin.partition,
+                                   offset = origin.offset, reason, location;
+                                   "Skipping a message whose processing panicked.");
+                        }
+                    }
+                }
+            }
+        }));
+    }
+    let mut source = KafkaSource::new(kafka_consumer, metadata_refresh);
+    let stop = || done(&processed_count) || SHUTDOWN_REQUESTED.load(Ordering::Relaxed);
+    source::consume(&mut source, &collector, stop, set_connected, |payload, origin| {
+        queue_depth.inc();
+        sender.send((payload, origin))
+    })?;
+
+    // Only reached with --max-messages or on shutdown with --state-file.
+    drop(sender);
+    let (joined_tx, joined_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for worker in workers {
+            worker.join().unwrap();
         }
+        let _ = joined_tx.send(());
+    });
+    let joined = if args.shutdown_timeout_seconds > 0 {
+        joined_rx.recv_timeout(Duration::from_secs(args.shutdown_timeout_seconds))
+    } else {
+        joined_rx.recv().map_err(mpsc::RecvTimeoutError::from)
+    };
+    match joined {
+        Ok(()) => {
+            if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+                info!("Processed the queued messages, shutting down.");
+            }
+        }
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            warn!(queue_depth = queue_depth.get();
+                  "Timed out processing the queued messages, shutting down anyway.");
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            return Err("A worker thread panicked.".into());
+        }
+    }
+    if let Some(path) = &args.state_file {
+        if let Err(err) = state::save(path, &state::snapshot(&collector.counters())) {
+            eprintln!("error: cannot save {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+    if args.max_messages.is_some() {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&gather(), &mut buffer)?;
+        std::io::stdout().write_all(&buffer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_options() {
+        assert!(is_secret_option("ssl-key-password"));
+        assert!(is_secret_option("token"));
+        assert!(!is_secret_option("secrets-dir"));
+        assert!(!is_secret_option("client-pkcs12-password-file"));
+    }
+
+    #[test]
+    fn effective_config_shows_secrets_dir() {
+        let matches = Args::command()
+            .try_get_matches_from(["dcache-kafka-exporter", "--secrets-dir", "/secrets"])
+            .unwrap();
+        let config = effective_config(&matches);
+        assert_eq!(config["secrets-dir"]["value"], "/secrets");
+        assert_eq!(config["secrets-dir"]["source"], "command line");
     }
 }
@@ -0,0 +1,223 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Reduction of free-form status messages to a bounded set of strings which
+// can be used as label values.  Variable parts like paths, addresses, IDs, and
+// numbers are replaced by placeholders.
+
+use std::path::Path;
+use regex::{Regex, RegexSet};
+use crate::anonymize::ip_pattern;
+
+// The maximum length of a rewritten message, in characters.
+const MAX_LENGTH : usize = 120;
+
+// Rules applied in order, so that more specific patterns come first.
+const DEFAULT_RULES : &[(&str, &str)] = &[
+    // PNFS IDs, as in "0000A1B2C3D4E5F6A7B8C9D0E1F2A3B4C5D6".
+    (r"\b[0-9A-F]{36}\b", "<pnfsid>"),
+    // UUIDs, as used for request and session IDs.  This must precede the
+    // rule for numbers, which would otherwise split them.
+    (r"\b[0-9a-fA-F]{8}(?:-[0-9a-fA-F]{4}){3}-[0-9a-fA-F]{12}\b", "<uuid>"),
+    // IP addresses as recognized by the anonymizer, optionally with a port.
+    (concat!(r"(?:", ip_pattern!(), r")(?::\d+\b)?"), "<ip>"),
+    // URLs, without trailing punctuation of the surrounding sentence.
+    (r"\b[a-z][a-z0-9+.-]*://\S*[^\s.,:;)\]]", "<url>"),
+    // Absolute paths with at least two components.
+    (r"(?:/[^\s/:;,'\x22()\[\]]+){2,}/?", "<path>"),
+    // Durations and sizes with units, as in "30s" or "12 MB".
+    (r"\b\d+(?:\.\d+)?\s?(?:ms|s|min|h|[KMGT]i?B)\b", "<n>"),
+    // Remaining numbers, including hexadecimal ones.
+    (r"\b(?:0x[0-9A-Fa-f]+|\d+(?:\.\d+)?)\b", "<n>"),
+    // Collapse whitespace, including newlines from script output.
+    (r"\s+", " "),
+];
+
+pub struct MessageRewriteRules {
+    rules: Vec<(Regex, String)>,
+    // All patterns of the rules, to find the rules matching a message in one
+    // pass instead of trying each in turn.
+    patterns: RegexSet,
+}
+
+impl Default for MessageRewriteRules {
+    fn default() -> MessageRewriteRules {
+        let rules = DEFAULT_RULES.iter()
+            .map(|(pattern, replacement)| {
+                (Regex::new(pattern).unwrap(), replacement.to_string())
+            })
+            .collect();
+        MessageRewriteRules::new(rules).unwrap()
+    }
+}
+
+impl MessageRewriteRules {
+    fn new(rules: Vec<(Regex, String)>) -> Result<MessageRewriteRules, regex::Error> {
+        let patterns = RegexSet::new(rules.iter().map(|(regex, _)| regex.as_str()))?;
+        Ok(MessageRewriteRules {rules, patterns})
+    }
+
+    // Loads rules from a file where each line holds a regular expression and
+    // its replacement separated by a tab.  Empty lines and lines starting
+    // with "#" are ignored.  The rules are applied before the default ones.
+    pub fn load(path: &Path) -> Result<MessageRewriteRules, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        let mut rules = vec![];
+        for (lineno, line) in content.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |msg: String| format!("{}:{}: {}", path.display(), lineno + 1, msg);
+            let (pattern, replacement) = line.split_once('\t')
+                .ok_or_else(|| error("expected a regular expression, a tab, and a replacement"
+                                     .to_string()))?;
+            let regex = Regex::new(pattern).map_err(|err| error(err.to_string()))?;
+            rules.push((regex, replacement.to_string()));
+        }
+        rules.extend(MessageRewriteRules::default().rules);
+        MessageRewriteRules::new(rules)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // The number of rules applied by rewrite.
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Rewrites a message by applying each rule to the result of the previous,
+    // then trims and truncates the result.  Rules which do not match are
+    // skipped.  As a replacement can make later rules match or stop matching,
+    // the matching rules are looked up again after each change.
+    pub fn rewrite(&self, msg: &str) -> String {
+        let mut msg = msg.to_string();
+        let mut matches = self.patterns.matches(&msg);
+        for (i, (regex, replacement)) in self.rules.iter().enumerate() {
+            if !matches.matched(i) {
+                continue;
+            }
+            if let std::borrow::Cow::Owned(rewritten) =
+                regex.replace_all(&msg, replacement.as_str())
+            {
+                msg = rewritten;
+                matches = self.patterns.matches(&msg);
+            }
+        }
+        let msg = msg.trim();
+        match msg.char_indices().nth(MAX_LENGTH) {
+            Some((end, _)) => { msg[..end].to_string() }
+            None => { msg.to_string() }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+    use super::MessageRewriteRules;
+
+    // The result of applying every rule in turn, without the prefilter.
+    fn rewrite_sequentially(rules: &MessageRewriteRules, msg: &str) -> String {
+        let mut msg = msg.to_string();
+        for (regex, replacement) in &rules.rules {
+            msg = regex.replace_all(&msg, replacement.as_str()).into_owned();
+        }
+        msg.trim().to_string()
+    }
+
+    #[test]
+    fn prefilter_is_equivalent() {
+        // The custom rules produce text which only later rules match, or
+        // remove text which later rules would have matched.
+        let custom = [(r"\bEIO\b", "errno 5"), (r"\bin \S+$", "")];
+        let mut rules: Vec<(Regex, String)> = custom.iter()
+            .map(|(pattern, replacement)| {
+                (Regex::new(pattern).unwrap(), replacement.to_string())
+            })
+            .collect();
+        rules.extend(MessageRewriteRules::default().rules);
+        let rules = MessageRewriteRules::new(rules).unwrap();
+        let corpus = [
+            "",
+            "Transfer aborted",
+            "Pool offline:  EIO",
+            "Pool unavailable in /pool/data",
+            "Connection to [2001:db8::1]:22 refused after 30s",
+            "Request 3f2a1b4c-1234-4abc-9def-0123456789ab failed with code 0x1f",
+            "Flush of 0000A1B2C3D4E5F6A7B8C9D0E1F2A3B4C5D6 failed: timeout after 3600 s",
+            "HSM script failed (script reported: 2):\n  mount failed\n  after 3 attempts\n",
+            "Error while copying /pnfs/data/file.root to osm://osm/?bfid=0000ABC: code EIO",
+        ];
+        for msg in corpus {
+            assert_eq!(rules.rewrite(msg), rewrite_sequentially(&rules, msg), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn uuid() {
+        let rules = MessageRewriteRules::default();
+        assert_eq!(rules.rewrite("Request 3f2a1b4c-1234-4abc-9def-0123456789ab failed"),
+                   "Request <uuid> failed");
+    }
+
+    #[test]
+    fn uuid_before_numbers() {
+        // Each group of this UUID would also match the rule for numbers.
+        let rules = MessageRewriteRules::default();
+        assert_eq!(rules.rewrite("Session 12345678-1234-1234-1234-123456789012 lost after 42 s"),
+                   "Session <uuid> lost after <n>");
+    }
+
+    #[test]
+    fn ip_addresses() {
+        let rules = MessageRewriteRules::default();
+        assert_eq!(rules.rewrite("Connection to 2001:db8:1:2::17 refused"),
+                   "Connection to <ip> refused");
+        assert_eq!(rules.rewrite("Connection to [::1]:22 or 192.0.2.1:2811 refused"),
+                   "Connection to [<ip>]:<n> or <ip> refused");
+        assert_eq!(rules.rewrite("Timeout at 12:30:45"), "Timeout at <n>:<n>:<n>");
+    }
+
+    #[test]
+    fn hsm_script_output() {
+        let rules = MessageRewriteRules::default();
+        let cases = [
+            ("HSM script failed (script reported: 32): ENDS: drive busy, retry in 300s",
+             "HSM script failed (script reported: <n>): ENDS: drive busy, retry in <n>"),
+            ("Error: rc=4 Could not read from tape: Input/output error on /dev/nst0",
+             "Error: rc=<n> Could not read from tape: Input/output error on <path>"),
+            ("Flush of 0000A1B2C3D4E5F6A7B8C9D0E1F2A3B4C5D6 failed: timeout after 3600 s",
+             "Flush of <pnfsid> failed: timeout after <n>"),
+            ("Restore of 0000A1B2C3D4E5F6A7B8C9D0E1F2A3B4C5D6 from tape failed: \
+              File not found on tape 10.1.2.3:5000",
+             "Restore of <pnfsid> from tape failed: File not found on tape <ip>"),
+            ("HSM script failed (script reported: 1): Error while copying \
+              /pnfs/ndgf.org/data/atlas/file.root to \
+              osm://osm/?store=atlas&group=tape&bfid=0000ABC: tape VOL123 not mounted",
+             "HSM script failed (script reported: <n>): Error while copying <path> to <url>: \
+              tape VOL123 not mounted"),
+            ("HSM script failed (script reported: 2):\n  mount failed\n  after 3 attempts\n",
+             "HSM script failed (script reported: <n>): mount failed after <n> attempts"),
+        ];
+        for (msg, expected) in cases {
+            assert_eq!(rules.rewrite(msg), expected, "{}", msg);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Periodic refresh of the Kafka cluster metadata, so that the consumer
+// follows partition reassignments.
+
+use std::time::{Duration, Instant};
+use kafka::client::KafkaClient;
+use log::{info, warn};
+use prometheus_exporter::prometheus::IntCounter;
+use rand::Rng;
+use crate::schema::{MetricSchema, describe};
+
+const INITIAL_BACKOFF : Duration = Duration::from_secs(1);
+
+pub struct MetadataRefresh {
+    interval: Duration,
+    // The delay after the last consecutive failure, if the last attempt failed.
+    backoff: Option<Duration>,
+    next_attempt: Instant,
+    errors: IntCounter,
+}
+
+impl MetadataRefresh {
+    pub fn new(interval: Duration, errors: IntCounter) -> MetadataRefresh {
+        MetadataRefresh {
+            interval,
+            backoff: None,
+            next_attempt: Instant::now() + interval,
+            errors,
+        }
+    }
+
+    pub fn schema(&self) -> Vec<MetricSchema> {
+        describe(&self.errors, "counter")
+    }
+
+    // Reloads the metadata if due, returning whether it succeeded if
+    // attempted.  After a failure, the next attempt is
+    // made after a jittered delay which doubles for each consecutive failure,
+    // up to the refresh interval.
+    pub fn refresh_if_due(&mut self, client: &mut KafkaClient) -> Option<bool> {
+        if Instant::now() < self.next_attempt {
+            return None;
+        }
+        match client.load_metadata_all() {
+            Ok(()) => {
+                if self.backoff.is_some() {
+                    info!("Reloaded Kafka metadata after earlier failures.");
+                }
+                self.backoff = None;
+                self.next_attempt = Instant::now() + self.interval;
+                Some(true)
+            }
+            Err(error) => {
+                self.errors.inc();
+                let backoff = match self.backoff {
+                    None => { INITIAL_BACKOFF }
+                    Some(backoff) => { (backoff * 2).min(self.interval) }
+                };
+                warn!(error:%, retry_seconds = backoff.as_secs();
+                      "Failed to reload Kafka metadata.");
+                self.backoff = Some(backoff);
+                let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+                self.next_attempt = Instant::now() + backoff.mul_f64(jitter);
+                Some(false)
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The names of the exported metrics, being the prefix followed by the name of
+// the metric, unless renamed to match the names used by another exporter.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+pub struct MetricNames {
+    prefix: String,
+    renames: HashMap<String, String>,
+}
+
+impl MetricNames {
+    pub fn new(prefix: String) -> MetricNames {
+        MetricNames {prefix, renames: HashMap::new()}
+    }
+
+    // Adds renames from a file where each line holds a default name,
+    // including the prefix, and the name to use instead separated by
+    // whitespace.  Empty lines and lines starting with "#" are ignored.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |msg: &str| format!("{}:{}: {}", path.display(), lineno + 1, msg);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, new_name] = fields[..] else {
+                return Err(error("expected a metric name followed by its new name"));
+            };
+            if self.renames.contains_key(name) {
+                return Err(error(&format!("{} is already renamed", name)));
+            }
+            if self.renames.values().any(|other| other == new_name) {
+                return Err(error(&format!("{} is already the new name of another metric",
+                                          new_name)));
+            }
+            self.renames.insert(name.to_string(), new_name.to_string());
+        }
+        Ok(())
+    }
+
+    // The exported name of the metric with the given unprefixed name.
+    pub fn get(&self, name: &str) -> String {
+        let name = self.prefix.clone() + name;
+        self.renames.get(&name).cloned().unwrap_or(name)
+    }
+}
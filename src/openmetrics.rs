@@ -0,0 +1,213 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// An encoder for the OpenMetrics text format, which the prometheus crate
+// does not provide.
+
+use std::io::Write;
+use prometheus_exporter::prometheus::{
+    Encoder, Result,
+    proto::{LabelPair, Metric, MetricFamily, MetricType},
+};
+use crate::exemplar;
+
+pub const OPENMETRICS_FORMAT : &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Units declared for metric names with these suffixes.
+const UNITS : &[&str] = &["seconds", "bytes"];
+
+#[derive(Default)]
+pub struct OpenMetricsEncoder;
+
+impl OpenMetricsEncoder {
+    pub fn new() -> OpenMetricsEncoder {
+        OpenMetricsEncoder
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(&self, metric_families: &[MetricFamily], writer: &mut W) -> Result<()> {
+        for mf in metric_families {
+            let metric_type = mf.get_field_type();
+            // The name of a counter family excludes the "_total" suffix of
+            // its sample, which a counter named in the Prometheus style
+            // already has.
+            let name = match metric_type {
+                MetricType::COUNTER => {
+                    mf.get_name().strip_suffix("_total").unwrap_or(mf.get_name())
+                }
+                _ => { mf.get_name() }
+            };
+            let type_name = match metric_type {
+                MetricType::COUNTER => { "counter" }
+                MetricType::GAUGE => { "gauge" }
+                MetricType::HISTOGRAM => { "histogram" }
+                MetricType::SUMMARY => { "summary" }
+                MetricType::UNTYPED => { "unknown" }
+            };
+            writeln!(writer, "# TYPE {} {}", name, type_name)?;
+            if let Some(unit) = UNITS.iter().find(|u| name.ends_with(&format!("_{}", u))) {
+                writeln!(writer, "# UNIT {} {}", name, unit)?;
+            }
+            if !mf.get_help().is_empty() {
+                writeln!(writer, "# HELP {} {}", name, escape(mf.get_help()))?;
+            }
+            for m in mf.get_metric() {
+                match metric_type {
+                    MetricType::COUNTER => {
+                        write_sample(writer, name, "_total", m, None, m.get_counter().get_value())?;
+                    }
+                    MetricType::GAUGE => {
+                        write_sample(writer, name, "", m, None, m.get_gauge().get_value())?;
+                    }
+                    MetricType::UNTYPED => {
+                        // Not produced by the prometheus crate.
+                    }
+                    MetricType::HISTOGRAM => {
+                        let h = m.get_histogram();
+                        let mut inf_seen = false;
+                        for b in h.get_bucket() {
+                            let upper_bound = b.get_upper_bound();
+                            inf_seen |= upper_bound == f64::INFINITY;
+                            write_bucket(writer, name, m, upper_bound,
+                                         b.get_cumulative_count())?;
+                        }
+                        if !inf_seen {
+                            write_bucket(writer, name, m, f64::INFINITY, h.get_sample_count())?;
+                        }
+                        write_sample(writer, name, "_sum", m, None, h.get_sample_sum())?;
+                        write_sample(writer, name, "_count", m, None,
+                                     h.get_sample_count() as f64)?;
+                    }
+                    MetricType::SUMMARY => {
+                        let s = m.get_summary();
+                        for q in s.get_quantile() {
+                            write_sample(writer, name, "", m,
+                                         Some(("quantile", &format_float(q.get_quantile()))),
+                                         q.get_value())?;
+                        }
+                        write_sample(writer, name, "_sum", m, None, s.get_sample_sum())?;
+                        write_sample(writer, name, "_count", m, None,
+                                     s.get_sample_count() as f64)?;
+                    }
+                }
+            }
+        }
+        writeln!(writer, "# EOF")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn write_sample<W: Write>(writer: &mut W, name: &str, suffix: &str, m: &Metric,
+                          extra_label: Option<(&str, &str)>, value: f64)
+    -> Result<()>
+{
+    write!(writer, "{}{}", name, suffix)?;
+    write_labels(writer, m.get_label(), extra_label)?;
+    write!(writer, " {}", format_float(value))?;
+    if m.get_timestamp_ms() != 0 {
+        // OpenMetrics timestamps are in seconds.
+        write!(writer, " {}", format_float(m.get_timestamp_ms() as f64 / 1000.0))?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+fn write_bucket<W: Write>(writer: &mut W, name: &str, m: &Metric, upper_bound: f64,
+                          count: u64)
+    -> Result<()>
+{
+    write!(writer, "{}_bucket", name)?;
+    write_labels(writer, m.get_label(), Some(("le", format_float(upper_bound).as_str())))?;
+    write!(writer, " {}", format_float(count as f64))?;
+    if let Some(e) = exemplar::lookup(name, m.get_label(), upper_bound) {
+        write!(writer, " # ")?;
+        write_labels(writer, &[], e.labels.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        write!(writer, " {} {}", format_float(e.value), format_float(e.timestamp))?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+fn write_labels<'a, W: Write>(writer: &mut W, pairs: &'a [LabelPair],
+                              extra_labels: impl IntoIterator<Item = (&'a str, &'a str)>)
+    -> Result<()>
+{
+    let labels = pairs.iter()
+        .map(|lp| (lp.get_name(), lp.get_value()))
+        .chain(extra_labels);
+    let mut separator = "{";
+    for (name, value) in labels {
+        write!(writer, "{}{}=\"{}\"", separator, name, escape(value))?;
+        separator = ",";
+    }
+    if separator == "," {
+        write!(writer, "}}")?;
+    }
+    Ok(())
+}
+
+// Escapes help texts and label values.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n").replace('"', "\\\"")
+}
+
+// Formats a number, using the canonical forms for infinities and integral
+// values, as required for the "le" and "quantile" labels.
+fn format_float(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v.is_infinite() {
+        if v > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    } else if v.fract() == 0.0 && v.abs() < 1e15 {
+        format!("{:.1}", v)
+    } else {
+        v.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_exporter::prometheus::{Counter, IntCounter, Registry};
+    use super::*;
+
+    #[test]
+    fn counter_total_suffix() {
+        let registry = Registry::new();
+        let cpu = Counter::new("process_cpu_seconds_total", "CPU time.").unwrap();
+        let events = IntCounter::new("event_count", "Events.").unwrap();
+        registry.register(Box::new(cpu.clone())).unwrap();
+        registry.register(Box::new(events.clone())).unwrap();
+        cpu.inc_by(1.5);
+        events.inc();
+        let mut buffer = vec![];
+        OpenMetricsEncoder::new().encode(&registry.gather(), &mut buffer).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "\
+            # TYPE event_count counter\n\
+            # HELP event_count Events.\n\
+            event_count_total 1.0\n\
+            # TYPE process_cpu_seconds counter\n\
+            # UNIT process_cpu_seconds seconds\n\
+            # HELP process_cpu_seconds CPU time.\n\
+            process_cpu_seconds_total 1.5\n\
+            # EOF\n");
+    }
+}
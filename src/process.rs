@@ -0,0 +1,145 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The standard process_* metrics of the exporter itself, read from /proc on
+// each scrape.  This does the job of the process collector of the prometheus
+// crate without pulling in its dependencies.
+
+use std::fs;
+use prometheus_exporter::prometheus::{
+    self, Counter, Gauge, IntGauge,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+};
+use crate::schema::{MetricSchema, describe};
+
+#[derive(Clone)]
+pub struct ProcessCollector {
+    cpu_seconds_total: Counter,
+    resident_memory_bytes: IntGauge,
+    virtual_memory_bytes: IntGauge,
+    open_fds: IntGauge,
+    max_fds: IntGauge,
+    start_time_seconds: Gauge,
+}
+
+// The fields of /proc/self/stat used here.
+struct Stat {
+    cpu_seconds: f64,
+    start_time_seconds: f64,
+    virtual_memory_bytes: i64,
+    resident_memory_bytes: i64,
+}
+
+fn read_stat() -> Option<Stat> {
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as i64;
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may contain spaces and parentheses, so the fields are
+    // counted from the end of it, starting with the third field.
+    let (_, rest) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3).and_then(|s| s.parse::<u64>().ok());
+    let boot_time = fs::read_to_string("/proc/stat").ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|s| s.trim().parse::<u64>().ok())?;
+    Some(Stat {
+        cpu_seconds: (field(14)? + field(15)?) as f64 / ticks_per_second,
+        start_time_seconds: boot_time as f64 + field(22)? as f64 / ticks_per_second,
+        virtual_memory_bytes: field(23)? as i64,
+        resident_memory_bytes: field(24)? as i64 * page_size,
+    })
+}
+
+fn max_fds() -> Option<i64> {
+    let mut limit = libc::rlimit {rlim_cur: 0, rlim_max: 0};
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    Some(limit.rlim_cur as i64)
+}
+
+impl ProcessCollector {
+    pub fn new() -> prometheus::Result<ProcessCollector> {
+        Ok(ProcessCollector {
+            cpu_seconds_total: Counter::new(
+                "process_cpu_seconds_total",
+                "Total user and system CPU time spent in seconds.")?,
+            resident_memory_bytes: IntGauge::new(
+                "process_resident_memory_bytes",
+                "Resident memory size in bytes.")?,
+            virtual_memory_bytes: IntGauge::new(
+                "process_virtual_memory_bytes",
+                "Virtual memory size in bytes.")?,
+            open_fds: IntGauge::new(
+                "process_open_fds",
+                "Number of open file descriptors.")?,
+            max_fds: IntGauge::new(
+                "process_max_fds",
+                "Maximum number of open file descriptors.")?,
+            start_time_seconds: Gauge::new(
+                "process_start_time_seconds",
+                "Start time of the process since unix epoch in seconds.")?,
+        })
+    }
+
+    pub fn schema(&self) -> Vec<MetricSchema> {
+        let mut schema = describe(&self.cpu_seconds_total, "counter");
+        for gauge in [&self.resident_memory_bytes as &dyn Collector, &self.virtual_memory_bytes,
+                      &self.open_fds, &self.max_fds, &self.start_time_seconds] {
+            schema.extend(describe(gauge, "gauge"));
+        }
+        schema
+    }
+
+    fn update(&self) {
+        if let Some(stat) = read_stat() {
+            let cpu_seconds = stat.cpu_seconds - self.cpu_seconds_total.get();
+            if cpu_seconds > 0.0 {
+                self.cpu_seconds_total.inc_by(cpu_seconds);
+            }
+            self.start_time_seconds.set(stat.start_time_seconds);
+            self.virtual_memory_bytes.set(stat.virtual_memory_bytes);
+            self.resident_memory_bytes.set(stat.resident_memory_bytes);
+        }
+        if let Ok(entries) = fs::read_dir("/proc/self/fd") {
+            self.open_fds.set(entries.count() as i64);
+        }
+        if let Some(max_fds) = max_fds() {
+            self.max_fds.set(max_fds);
+        }
+    }
+}
+
+impl Collector for ProcessCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        [&self.cpu_seconds_total as &dyn Collector, &self.resident_memory_bytes,
+         &self.virtual_memory_bytes, &self.open_fds, &self.max_fds, &self.start_time_seconds]
+            .into_iter()
+            .flat_map(|metric| metric.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.update();
+        [&self.cpu_seconds_total as &dyn Collector, &self.resident_memory_bytes,
+         &self.virtual_memory_bytes, &self.open_fds, &self.max_fds, &self.start_time_seconds]
+            .into_iter()
+            .flat_map(|metric| metric.collect())
+            .collect()
+    }
+}
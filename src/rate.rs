@@ -0,0 +1,79 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Exponentially weighted moving averages of byte rates, for displays which
+// cannot compute rates from the counters.  Bytes are accumulated as events
+// arrive and folded into the averages when decayed, which should be done at
+// regular intervals of about DECAY_INTERVAL.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use prometheus_exporter::prometheus::GaugeVec;
+
+pub const DECAY_INTERVAL : Duration = Duration::from_secs(5);
+
+// The time for the weight of past rates to fall by a factor e, as for the
+// one-minute load average.
+const TIME_CONSTANT : Duration = Duration::from_secs(60);
+
+pub struct RateGaugeVec {
+    gauges: GaugeVec,
+    state: Mutex<RateState>,
+}
+
+struct RateState {
+    last_decay: Instant,
+    // The bytes seen since the last decay, per label combination.
+    pending: HashMap<Vec<String>, u64>,
+}
+
+impl RateGaugeVec {
+    pub fn new(gauges: GaugeVec) -> RateGaugeVec {
+        RateGaugeVec {
+            gauges,
+            state: Mutex::new(RateState {last_decay: Instant::now(), pending: HashMap::new()}),
+        }
+    }
+
+    pub fn gauges(&self) -> &GaugeVec {
+        &self.gauges
+    }
+
+    pub fn add(&self, label_values: &[&str], bytes: u64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = label_values.iter().map(|value| value.to_string()).collect();
+        *state.pending.entry(key).or_default() += bytes;
+    }
+
+    // Folds the bytes seen since the last call into the averages.
+    pub fn decay(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let elapsed = state.last_decay.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        state.last_decay = Instant::now();
+        let alpha = 1.0 - (-elapsed / TIME_CONSTANT.as_secs_f64()).exp();
+        for (key, bytes) in state.pending.iter_mut() {
+            let label_values: Vec<&str> = key.iter().map(String::as_str).collect();
+            let gauge = self.gauges.with_label_values(&label_values);
+            let rate = gauge.get();
+            gauge.set(rate + alpha * (*bytes as f64 / elapsed - rate));
+            *bytes = 0;
+        }
+    }
+}
@@ -0,0 +1,60 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A bounded buffer of the most recently processed messages, for debugging.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Entry {
+    timestamp: f64,
+    message: String,
+}
+
+pub struct RecentMessages {
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl RecentMessages {
+    pub fn new(capacity: usize) -> RecentMessages {
+        RecentMessages {capacity, entries: Mutex::new(VecDeque::with_capacity(capacity))}
+    }
+
+    // Records a message, dropping the oldest one if full.
+    pub fn push(&self, message: &str) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64()).unwrap_or(0.0);
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {timestamp, message: message.to_string()});
+    }
+
+    // The messages from oldest to newest, one JSON object per line.
+    pub fn to_json_lines(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        for entry in self.entries.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            serde_json::to_writer(&mut buffer, entry).unwrap();
+            buffer.push(b'\n');
+        }
+        buffer
+    }
+}
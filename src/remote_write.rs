@@ -0,0 +1,228 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Pushing the metrics of the default registry to a Prometheus remote-write
+// endpoint, for sites which do not allow scraping.  The protobuf encoding of
+// the WriteRequest message and the HTTP request are done by hand, as only
+// these small parts of the protocols are needed.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use openssl::ssl::{SslConnector, SslMethod};
+use prometheus_exporter::prometheus::{IntCounter, gather};
+use prometheus_exporter::prometheus::proto::{LabelPair, MetricFamily, MetricType};
+
+const TIMEOUT : Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    // Parses an http or https URL.
+    pub fn parse(url: &str) -> Result<Endpoint, String> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err("expected an http or https URL".to_string());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => { (&rest[..i], &rest[i..]) }
+            None => { (rest, "/") }
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| format!("invalid port {}", port))?)
+            }
+            _ => { (authority, default_port) }
+        };
+        if host.is_empty() {
+            return Err("missing host".to_string());
+        }
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+        Ok(Endpoint {tls, host, port, path: path.to_string()})
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}{}", scheme, self.host, self.port, self.path)
+        } else {
+            write!(f, "{}://{}:{}{}", scheme, self.host, self.port, self.path)
+        }
+    }
+}
+
+// Starts pushing the metrics at the given interval on a background thread.
+pub fn start(endpoint: Endpoint, interval: Duration, errors: IntCounter) {
+    thread::spawn(move || {
+        info!("Pushing metrics to {} every {}s", endpoint, interval.as_secs());
+        loop {
+            thread::sleep(interval);
+            if let Err(error) = push(&endpoint) {
+                errors.inc();
+                warn!(error:%; "Failed to push metrics.");
+            }
+        }
+    });
+}
+
+fn push(endpoint: &Endpoint) -> io::Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+    let body = snap::raw::Encoder::new().compress_vec(&write_request(&gather(), timestamp))?;
+    let stream = TcpStream::connect((endpoint.host.as_str(), endpoint.port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let response = if endpoint.tls {
+        let connector = SslConnector::builder(SslMethod::tls_client())
+            .map_err(io::Error::other)?
+            .build();
+        let mut stream = connector.connect(&endpoint.host, stream)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        post(&mut stream, endpoint, &body)?
+    } else {
+        post(&mut &stream, endpoint, &body)?
+    };
+    let status_line = response.lines().next().unwrap_or("");
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => { Ok(()) }
+        _ => { Err(io::Error::other(format!("unexpected response: {}", status_line))) }
+    }
+}
+
+// Sends the request and returns the response, of which only the status line
+// is of interest.
+fn post<S: Read + Write>(stream: &mut S, endpoint: &Endpoint, body: &[u8]) -> io::Result<String> {
+    write!(stream, "POST {} HTTP/1.1\r\n", endpoint.path)?;
+    write!(stream, "Host: {}:{}\r\n", endpoint.host, endpoint.port)?;
+    write!(stream, "Content-Type: application/x-protobuf\r\n")?;
+    write!(stream, "Content-Encoding: snappy\r\n")?;
+    write!(stream, "X-Prometheus-Remote-Write-Version: 0.1.0\r\n")?;
+    write!(stream, "Content-Length: {}\r\nConnection: close\r\n\r\n", body.len())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    let mut response = vec![];
+    stream.take(4096).read_to_end(&mut response)?;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+// The time series of the metric families, as an encoded WriteRequest.
+fn write_request(families: &[MetricFamily], timestamp: i64) -> Vec<u8> {
+    let mut buf = vec![];
+    for family in families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            let mut series = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let time_series = time_series(
+                    &(name.to_string() + suffix), labels, extra, value, timestamp);
+                field_bytes(&mut buf, 1, &time_series);
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => { series("", None, metric.get_counter().get_value()) }
+                MetricType::GAUGE => { series("", None, metric.get_gauge().get_value()) }
+                MetricType::UNTYPED => {
+                    // Not produced by the prometheus crate.
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut inf_seen = false;
+                    for bucket in histogram.get_bucket() {
+                        let upper_bound = bucket.get_upper_bound();
+                        inf_seen |= upper_bound == f64::INFINITY;
+                        let le =
+                            if inf_seen { "+Inf".to_string() } else { upper_bound.to_string() };
+                        series("_bucket", Some(("le", le)), bucket.get_cumulative_count() as f64);
+                    }
+                    let count = histogram.get_sample_count() as f64;
+                    if !inf_seen {
+                        series("_bucket", Some(("le", "+Inf".to_string())), count);
+                    }
+                    series("_sum", None, histogram.get_sample_sum());
+                    series("_count", None, count);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        series("", Some(("quantile", quantile.get_quantile().to_string())),
+                               quantile.get_value());
+                    }
+                    series("_sum", None, summary.get_sample_sum());
+                    series("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    buf
+}
+
+// An encoded TimeSeries with a single sample.  The labels must be sorted by
+// name.
+fn time_series(name: &str, labels: &[LabelPair], extra: Option<(&str, String)>,
+               value: f64, timestamp: i64) -> Vec<u8> {
+    let mut pairs: Vec<(&str, &str)> = labels.iter()
+        .map(|pair| (pair.get_name(), pair.get_value()))
+        .collect();
+    pairs.push(("__name__", name));
+    if let Some((label_name, label_value)) = &extra {
+        pairs.push((label_name, label_value));
+    }
+    pairs.sort();
+    let mut buf = vec![];
+    for (label_name, label_value) in pairs {
+        let mut label = vec![];
+        field_bytes(&mut label, 1, label_name.as_bytes());
+        field_bytes(&mut label, 2, label_value.as_bytes());
+        field_bytes(&mut buf, 1, &label);
+    }
+    let mut sample = vec![];
+    field_key(&mut sample, 1, 1);
+    sample.extend(value.to_le_bytes());
+    field_key(&mut sample, 2, 0);
+    varint(&mut sample, timestamp as u64);
+    field_bytes(&mut buf, 2, &sample);
+    buf
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn field_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    varint(buf, field << 3 | wire_type);
+}
+
+fn field_bytes(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+    field_key(buf, field, 2);
+    varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
@@ -0,0 +1,42 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A description of the exported metrics, for generating documentation and
+// dashboards.
+
+use prometheus_exporter::prometheus::core::Collector;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct MetricSchema {
+    name: String,
+    #[serde(rename = "type")]
+    type_: &'static str,
+    help: String,
+    labels: Vec<String>,
+}
+
+// Describes the metrics of a collector, which are all of the given type.
+pub fn describe(collector: &dyn Collector, type_: &'static str) -> Vec<MetricSchema> {
+    collector.desc().into_iter()
+        .map(|desc| MetricSchema {
+            name: desc.fq_name.clone(),
+            type_,
+            help: desc.help.clone(),
+            labels: desc.variable_labels.clone(),
+        })
+        .collect()
+}
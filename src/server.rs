@@ -0,0 +1,330 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The HTTP(S) server exposing the metrics of the default registry.
+
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{error, info};
+use openssl::memcmp;
+use prometheus_exporter::prometheus::{Encoder, TextEncoder, gather};
+use crate::openmetrics::OpenMetricsEncoder;
+use crate::recent::RecentMessages;
+use tiny_http::{Header, Response, Server, SslConfig};
+
+const DEBUG_MESSAGES_PATH : &str = "/debug/messages";
+const CONFIG_PATH : &str = "/config";
+
+pub type StartError = Box<dyn Error + Send + Sync + 'static>;
+
+#[derive(Clone)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(feature = "unix-socket")]
+    Unix(std::path::PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => { write!(f, "{}", addr) }
+            #[cfg(feature = "unix-socket")]
+            ListenAddr::Unix(path) => { write!(f, "unix:{}", path.display()) }
+        }
+    }
+}
+
+pub struct Config {
+    pub listen: ListenAddr,
+    // The path to serve metrics at.
+    pub metrics_path: String,
+    // Serve over TLS if given.
+    pub ssl: Option<SslConfig>,
+    // Require requests to present this as a bearer token if given.
+    pub auth_token: Option<String>,
+    // Serve these at /debug/messages if given.
+    pub debug_messages: Option<Arc<RecentMessages>>,
+    // Served at /config to show the options in effect.
+    pub effective_config: Vec<u8>,
+}
+
+// The state needed to answer requests.
+struct Handler {
+    metrics_path: String,
+    auth_token: Option<String>,
+    debug_messages: Option<Arc<RecentMessages>>,
+    effective_config: Vec<u8>,
+}
+
+// A response independent of the transport.
+struct Reply {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn text(status: u16, body: String) -> Reply {
+        Reply {status, headers: vec![], body: body.into_bytes()}
+    }
+
+    fn with_header(mut self, field: &'static str, value: &str) -> Reply {
+        self.headers.push((field, value.to_string()));
+        self
+    }
+}
+
+// Starts serving metrics on a background thread.
+pub fn start(config: Config) -> Result<(), StartError> {
+    let handler = Handler {
+        metrics_path: config.metrics_path,
+        auth_token: config.auth_token,
+        debug_messages: config.debug_messages,
+        effective_config: config.effective_config,
+    };
+    match config.listen {
+        ListenAddr::Tcp(listen) => { start_tcp(listen, config.ssl, handler) }
+        #[cfg(feature = "unix-socket")]
+        ListenAddr::Unix(path) => {
+            if config.ssl.is_some() {
+                return Err("TLS is not supported on Unix sockets".into());
+            }
+            unix::start(path, handler)
+        }
+    }
+}
+
+fn start_tcp(listen: SocketAddr, ssl: Option<SslConfig>, handler: Handler)
+    -> Result<(), StartError>
+{
+    let scheme = if ssl.is_some() { "https" } else { "http" };
+    let server = match ssl {
+        Some(ssl) => { Server::https(listen, ssl)? }
+        None => { Server::http(listen)? }
+    };
+    thread::spawn(move || {
+        info!("Exporting metrics to {}://{}{}", scheme, listen, handler.metrics_path);
+        for request in server.incoming_requests() {
+            let headers: Vec<(&str, &str)> = request.headers().iter()
+                .map(|header| (header.field.as_str().as_str(), header.value.as_str()))
+                .collect();
+            let reply = handler.handle(request.url(), &headers);
+            let mut response = Response::from_data(reply.body)
+                .with_status_code(reply.status);
+            for (field, value) in reply.headers {
+                response.add_header(Header::from_bytes(field, value).unwrap());
+            }
+            if let Err(err) = request.respond(response) {
+                error!("Failed to respond to HTTP request: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+// The values of the request headers with the given name.
+fn header_values<'a>(headers: &[(&str, &'a str)], field: &str) -> Vec<&'a str> {
+    headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(field))
+        .map(|(_, value)| *value)
+        .collect()
+}
+
+fn is_authorized(headers: &[(&str, &str)], auth_token: &str) -> bool {
+    header_values(headers, "Authorization").iter()
+        .filter_map(|value| value.strip_prefix("Bearer "))
+        .any(|token| {
+            let (token, expected) = (token.trim().as_bytes(), auth_token.as_bytes());
+            token.len() == expected.len() && memcmp::eq(token, expected)
+        })
+}
+
+fn accepts_openmetrics(headers: &[(&str, &str)]) -> bool {
+    header_values(headers, "Accept").iter()
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            media_range.split(';').next().unwrap().trim()
+                .eq_ignore_ascii_case("application/openmetrics-text")
+        })
+}
+
+fn accepts_gzip(headers: &[(&str, &str)]) -> bool {
+    header_values(headers, "Accept-Encoding").iter()
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap().trim();
+            let rejected = params.any(|param| {
+                param.trim().strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f64>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            name.eq_ignore_ascii_case("gzip") && !rejected
+        })
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::fast());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+fn encode<E: Encoder>(encoder: E) -> Reply {
+    let mut buffer = vec![];
+    if let Err(err) = encoder.encode(&gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", err);
+        return Reply::text(500, "failed to encode metrics\n".to_string());
+    }
+    Reply {status: 200, headers: vec![], body: buffer}
+        .with_header("Content-Type", encoder.format_type())
+}
+
+impl Handler {
+    fn handle(&self, url: &str, headers: &[(&str, &str)]) -> Reply {
+        if let Some(auth_token) = &self.auth_token {
+            if !is_authorized(headers, auth_token) {
+                return Reply::text(401, "unauthorized\n".to_string())
+                    .with_header("WWW-Authenticate", "Bearer");
+            }
+        }
+        // Route on the path alone, as scrapers may add a query string.
+        let url = url.split_once('?').map_or(url, |(path, _)| path);
+        if let (DEBUG_MESSAGES_PATH, Some(debug_messages)) = (url, &self.debug_messages) {
+            return Reply {status: 200, headers: vec![], body: debug_messages.to_json_lines()}
+                .with_header("Content-Type", "application/jsonl");
+        }
+        if url == CONFIG_PATH {
+            return Reply {status: 200, headers: vec![], body: self.effective_config.clone()}
+                .with_header("Content-Type", "application/json");
+        }
+        if url != self.metrics_path {
+            return Reply::text(404, format!("try {} for metrics\n", self.metrics_path));
+        }
+        let reply = if accepts_openmetrics(headers) {
+            encode(OpenMetricsEncoder::new())
+        } else {
+            encode(TextEncoder::new())
+        };
+        if reply.status != 200 || !accepts_gzip(headers) {
+            return reply;
+        }
+        match gzip(&reply.body) {
+            Ok(body) => {
+                Reply {body, ..reply}
+                    .with_header("Content-Encoding", "gzip")
+                    .with_header("Vary", "Accept-Encoding")
+            }
+            Err(err) => {
+                error!("Failed to compress metrics: {}", err);
+                reply
+            }
+        }
+    }
+}
+
+// A minimal HTTP/1.1 server on a Unix socket, answering one request per
+// connection.
+#[cfg(feature = "unix-socket")]
+mod unix {
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::thread;
+    use log::{error, info};
+    use super::{Handler, StartError};
+
+    pub(super) fn start(path: PathBuf, handler: Handler) -> Result<(), StartError> {
+        // Remove a socket left behind by a previous instance.
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        let listener = UnixListener::bind(&path)?;
+        thread::spawn(move || {
+            info!("Exporting metrics to unix:{}{}", path.display(), handler.metrics_path);
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| serve(stream, &handler));
+                if let Err(err) = result {
+                    error!("Failed to respond to HTTP request: {}", err);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn serve(stream: UnixStream, handler: &Handler) -> io::Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let url = request_line.split_whitespace().nth(1).unwrap_or("").to_string();
+        let mut header_lines = vec![];
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                break;
+            }
+            header_lines.push(line);
+        }
+        let headers: Vec<(&str, &str)> = header_lines.iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(field, value)| (field.trim(), value.trim()))
+            .collect();
+        let reply = handler.handle(&url, &headers);
+        let mut writer = &stream;
+        write!(writer, "HTTP/1.1 {} {}\r\n", reply.status, reason(reply.status))?;
+        for (field, value) in &reply.headers {
+            write!(writer, "{}: {}\r\n", field, value)?;
+        }
+        write!(writer, "Content-Length: {}\r\nConnection: close\r\n\r\n", reply.body.len())?;
+        writer.write_all(&reply.body)?;
+        writer.flush()
+    }
+
+    fn reason(status: u16) -> &'static str {
+        match status {
+            200 => { "OK" }
+            401 => { "Unauthorized" }
+            404 => { "Not Found" }
+            _ => { "Internal Server Error" }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_query_string() {
+        let handler = Handler {
+            metrics_path: "/metrics".to_string(),
+            auth_token: None,
+            debug_messages: None,
+            effective_config: b"{}".to_vec(),
+        };
+        assert_eq!(handler.handle("/metrics?name[]=up", &[]).status, 200);
+        assert_eq!(handler.handle("/config?pretty", &[]).body, b"{}");
+        assert_eq!(handler.handle("/other?/metrics", &[]).status, 404);
+    }
+}
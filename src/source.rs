@@ -0,0 +1,242 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Where the consume loop gets its records from.  Besides the Kafka consumer,
+// records can be supplied from memory, so that the loop can be driven without
+// a broker.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use kafka::consumer::Consumer;
+use log::warn;
+use crate::collector::{Collector, Origin};
+use crate::metadata::MetadataRefresh;
+
+pub struct Record {
+    pub offset: i64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+// Consecutive records from one partition.
+pub struct Batch {
+    pub topic: Arc<str>,
+    pub partition: i32,
+    pub records: Vec<Record>,
+}
+
+pub trait MessageSource {
+    type Error: fmt::Display;
+
+    // Fetches the records which have arrived since the last poll, which may
+    // be none.
+    fn poll(&mut self) -> Result<Vec<Batch>, Self::Error>;
+
+    // Whether the source is fully connected after a successful poll.
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+impl MessageSource for Consumer {
+    type Error = kafka::Error;
+
+    fn poll(&mut self) -> Result<Vec<Batch>, kafka::Error> {
+        let message_sets = Consumer::poll(self)?;
+        let batches = message_sets.iter()
+            .map(|msgs| {
+                Batch {
+                    topic: Arc::from(msgs.topic()),
+                    partition: msgs.partition(),
+                    records: msgs.messages().iter()
+                        .map(|msg| {
+                            Record {offset: msg.offset, key: msg.key.to_vec(),
+                                    value: msg.value.to_vec()}
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        Ok(batches)
+    }
+}
+
+// The Kafka consumer, reloading the cluster metadata before polling when
+// due.
+pub struct KafkaSource {
+    consumer: Consumer,
+    metadata_refresh: Option<MetadataRefresh>,
+    // Whether the last attempt to reload the metadata succeeded.
+    metadata_loaded: bool,
+}
+
+impl KafkaSource {
+    pub fn new(consumer: Consumer, metadata_refresh: Option<MetadataRefresh>) -> KafkaSource {
+        KafkaSource {consumer, metadata_refresh, metadata_loaded: true}
+    }
+}
+
+impl MessageSource for KafkaSource {
+    type Error = kafka::Error;
+
+    fn poll(&mut self) -> Result<Vec<Batch>, kafka::Error> {
+        // A failed reload is logged and retried, and polling may still
+        // succeed with the metadata already known.
+        if let Some(metadata_refresh) = &mut self.metadata_refresh {
+            if let Some(loaded) = metadata_refresh.refresh_if_due(self.consumer.client_mut()) {
+                self.metadata_loaded = loaded;
+            }
+        }
+        MessageSource::poll(&mut self.consumer)
+    }
+
+    // Not while the metadata fails to reload, though polling succeeds.
+    fn is_connected(&self) -> bool {
+        self.metadata_loaded
+    }
+}
+
+// Canned batches, delivered one per poll.  Polls after the last batch
+// return no records.
+#[derive(Default)]
+pub struct MemorySource {
+    batches: VecDeque<Batch>,
+    next_offsets: HashMap<(String, i32), i64>,
+}
+
+impl MemorySource {
+    // Adds a batch of payloads on the given topic and partition, with offsets
+    // following those already added for the partition.
+    pub fn push(&mut self, topic: &str, partition: i32, payloads: &[&[u8]]) {
+        let next_offset = self.next_offsets.entry((topic.to_string(), partition)).or_insert(0);
+        let records = payloads.iter()
+            .map(|payload| {
+                *next_offset += 1;
+                Record {offset: *next_offset - 1, key: vec![], value: payload.to_vec()}
+            })
+            .collect();
+        self.batches.push_back(Batch {topic: Arc::from(topic), partition, records});
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+}
+
+impl MessageSource for MemorySource {
+    type Error = Infallible;
+
+    fn poll(&mut self) -> Result<Vec<Batch>, Infallible> {
+        Ok(self.batches.pop_front().into_iter().collect())
+    }
+}
+
+// Polls the source until `done` returns true, passing each message worth
+// processing to `dispatch` along with where it came from.  Whether the
+// source is connected after each poll is passed to `set_connected`, and
+// failed polls are retried after a second.
+pub fn consume<S, E>(
+    source: &mut S, collector: &Collector, done: impl Fn() -> bool,
+    mut set_connected: impl FnMut(bool), mut dispatch: impl FnMut(Vec<u8>, Origin) -> Result<(), E>,
+) -> Result<(), E>
+    where S: MessageSource
+{
+    while !done() {
+        let batches = match source.poll() {
+            Ok(batches) => {
+                set_connected(source.is_connected());
+                batches
+            }
+            Err(error) => {
+                set_connected(false);
+                warn!(error:%; "Failed to poll Kafka.");
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        for batch in batches {
+            collector.count_consumed(&batch);
+            for record in batch.records {
+                if let Some(origin) = collector.accept(&batch.topic, batch.partition, &record) {
+                    dispatch(record.value, origin)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use prometheus_exporter::prometheus::Registry;
+    use crate::collector::Config;
+    use super::*;
+
+    const REMOVE : &str = r#"{"msgType": "remove", "billingPath": "/", "cellName": "PnfsManager",
+        "cellType": "PnfsManager", "cellDomain": "namespaceDomain", "fileSize": 1000,
+        "pnfsid": "0000ABCD", "queuingTime": 0, "session": "s", "storageInfo": null,
+        "status": {"code": 0, "msg": ""}, "subject": [], "transaction": null}"#;
+
+    // The values of the series of a metric family, by partition if labelled
+    // with one.
+    fn values(registry: &Registry, name: &str) -> Vec<(String, f64)> {
+        registry.gather().iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let partition = metric.get_label().iter()
+                    .find(|label| label.get_name() == "partition")
+                    .map_or(String::new(), |label| label.get_value().to_string());
+                let value = metric.get_counter().get_value() + metric.get_gauge().get_value();
+                (partition, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn memory_source() {
+        let registry = Registry::new();
+        let config = Config {max_message_bytes: REMOVE.len(), ..Config::default()};
+        let collector = Collector::new(config, &registry).unwrap();
+        let oversize = format!("{} ", REMOVE);
+        let mut source = MemorySource::default();
+        source.push("billing", 0, &[REMOVE.as_bytes(), b"", REMOVE.as_bytes()]);
+        source.push("billing", 1, &[oversize.as_bytes(), REMOVE.as_bytes()]);
+
+        let processed = Cell::new(0);
+        let mut polls = 0;
+        consume(&mut source, &collector, || processed.get() == 3, |_| { polls += 1 },
+                |payload, origin| {
+                    processed.set(processed.get() + collector.process_payload(&payload, &origin));
+                    Ok::<(), Infallible>(())
+                }).unwrap();
+        assert!(source.is_empty());
+        assert_eq!(polls, 2);
+        assert_eq!(values(&registry, "dcache_kafka_remove_count"), [(String::new(), 3.0)]);
+        assert_eq!(values(&registry, "dcache_kafka_tombstone_count"), [(String::new(), 1.0)]);
+        assert_eq!(values(&registry, "dcache_kafka_oversize_dropped_count"),
+                   [(String::new(), 1.0)]);
+        assert_eq!(values(&registry, "dcache_kafka_consumed_count"),
+                   [("0".to_string(), 3.0), ("1".to_string(), 2.0)]);
+        assert_eq!(values(&registry, "dcache_kafka_current_offset"),
+                   [("0".to_string(), 2.0), ("1".to_string(), 1.0)]);
+    }
+}
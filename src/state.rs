@@ -0,0 +1,120 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Preserving counter values across restarts.  The values are saved to a JSON
+// file on graceful shutdown and added back to the counters on startup.  A
+// saved series is only restored if the counter still has exactly the same
+// label names, so changing the label options between runs drops the
+// affected counters.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use prometheus_exporter::prometheus::{CounterVec, IntCounter, IntCounterVec};
+use prometheus_exporter::prometheus::core::Collector;
+use prometheus_exporter::prometheus::proto::MetricType;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct Sample {
+    name: String,
+    labels: BTreeMap<String, String>,
+    value: f64,
+}
+
+// A counter which can be seeded with a saved value.
+pub trait Counter: Collector {
+    fn restore(&self, label_values: &[&str], value: f64);
+}
+
+impl Counter for IntCounter {
+    fn restore(&self, _label_values: &[&str], value: f64) {
+        self.inc_by(value as u64);
+    }
+}
+
+impl Counter for IntCounterVec {
+    fn restore(&self, label_values: &[&str], value: f64) {
+        self.with_label_values(label_values).inc_by(value as u64);
+    }
+}
+
+impl Counter for CounterVec {
+    fn restore(&self, label_values: &[&str], value: f64) {
+        self.with_label_values(label_values).inc_by(value);
+    }
+}
+
+// The current values of the given counters.
+pub fn snapshot(counters: &[&dyn Counter]) -> Vec<Sample> {
+    let mut samples = vec![];
+    for family in counters.iter().flat_map(|counter| counter.collect()) {
+        if family.get_field_type() != MetricType::COUNTER {
+            continue;
+        }
+        for metric in family.get_metric() {
+            samples.push(Sample {
+                name: family.get_name().to_string(),
+                labels: metric.get_label().iter()
+                    .map(|pair| (pair.get_name().to_string(), pair.get_value().to_string()))
+                    .collect(),
+                value: metric.get_counter().get_value(),
+            });
+        }
+    }
+    samples
+}
+
+// Adds the saved values to the given counters, returning the number of
+// samples which did not match any counter.
+pub fn restore(counters: &[&dyn Counter], samples: &[Sample]) -> usize {
+    let mut restored = 0;
+    for counter in counters {
+        for desc in counter.desc() {
+            for sample in samples.iter().filter(|sample| sample.name == desc.fq_name) {
+                if sample.labels.len() != desc.variable_labels.len() {
+                    continue;
+                }
+                let label_values: Option<Vec<&str>> = desc.variable_labels.iter()
+                    .map(|name| sample.labels.get(name).map(String::as_str))
+                    .collect();
+                if let Some(label_values) = label_values {
+                    counter.restore(&label_values, sample.value);
+                    restored += 1;
+                }
+            }
+        }
+    }
+    samples.len() - restored
+}
+
+// Loads saved values, treating a missing file as empty.
+pub fn load(path: &Path) -> io::Result<Vec<Sample>> {
+    match fs::read(path) {
+        Ok(data) => { Ok(serde_json::from_slice(&data)?) }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => { Ok(vec![]) }
+        Err(err) => { Err(err) }
+    }
+}
+
+// Saves the values, replacing the file atomically.
+pub fn save(path: &Path, samples: &[Sample]) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, serde_json::to_vec(samples)?)?;
+    fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,73 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Classification of the status codes of billing records into categories
+// suitable as a label.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+// Well-known codes of dCache's CacheException.
+const BUILTIN_CATEGORIES : &[(u32, &str)] = &[
+    (0, "ok"),
+    (10001, "no-such-file"),
+    (10006, "timeout"),
+    (10008, "file-exists"),
+    (10014, "file-not-online"),
+    (10016, "no-pool"),
+    (10017, "no-pool"),
+    (10021, "service-unavailable"),
+];
+
+pub struct StatusCategories {
+    categories: HashMap<u32, String>,
+}
+
+impl StatusCategories {
+    pub fn builtin() -> StatusCategories {
+        let categories = BUILTIN_CATEGORIES.iter()
+            .map(|(code, category)| (*code, category.to_string()))
+            .collect();
+        StatusCategories {categories}
+    }
+
+    // Adds or overrides categories from a file where each line holds a code
+    // and a category separated by whitespace.  Empty lines and lines starting
+    // with "#" are ignored.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = line.split_once(char::is_whitespace)
+                .and_then(|(code, category)| Some((code.parse().ok()?, category.trim())));
+            let Some((code, category)) = entry else {
+                return Err(format!("{}:{}: expected a status code followed by a category",
+                                   path.display(), lineno + 1));
+            };
+            self.categories.insert(code, category.to_string());
+        }
+        Ok(())
+    }
+
+    // The category of a status code, or "other" if unknown.
+    pub fn category(&self, code: u32) -> &str {
+        self.categories.get(&code).map(|category| category.as_str()).unwrap_or("other")
+    }
+}
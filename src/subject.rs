@@ -0,0 +1,73 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Interpretation of the principals of the subject of billing records.  dCache
+// renders each principal as e.g. "UidPrincipal[1000]",
+// "GidPrincipal[1000,primary]", or
+// "FQANPrincipal[/atlas/Role=production,primary]".
+
+// Splits a principal into its type and arguments.
+fn parse_principal(principal: &str) -> Option<(&str, Vec<&str>)> {
+    let (type_, rest) = principal.split_once('[')?;
+    let args = rest.strip_suffix(']')?;
+    Some((type_, args.split(',').collect()))
+}
+
+// The VO of the primary FQAN, or of the first FQAN if none is marked as
+// primary.  The VO is the first component of the FQAN group.
+pub fn primary_vo(subject: &[String]) -> Option<&str> {
+    let mut first_vo = None;
+    for principal in subject {
+        let Some(("FQANPrincipal", args)) = parse_principal(principal) else {
+            continue;
+        };
+        let vo = args[0].trim_start_matches('/').split('/').next().unwrap_or("");
+        if vo.is_empty() {
+            continue;
+        }
+        if args[1..].contains(&"primary") {
+            return Some(vo);
+        }
+        first_vo.get_or_insert(vo);
+    }
+    first_vo
+}
+
+// A coarse classification of the kind of authentication a principal stems
+// from, revealing nothing about the identity.
+fn principal_type(principal: &str) -> &'static str {
+    let type_ = principal.split_once('[').map_or(principal, |(type_, _)| type_);
+    match type_ {
+        "GlobusPrincipal" => { "dn" }
+        "KerberosPrincipal" => { "kerberos" }
+        "UidPrincipal" => { "uid" }
+        _ if ["Oidc", "OpenId", "Jwt", "BearerToken", "OAuth"].iter()
+                .any(|prefix| type_.starts_with(prefix)) => { "token" }
+        _ => { "other" }
+    }
+}
+
+// The distinct principal types of a subject, in order of appearance.
+pub fn principal_types(subject: &[String]) -> Vec<&'static str> {
+    let mut types = vec![];
+    for principal in subject {
+        let type_ = principal_type(principal);
+        if !types.contains(&type_) {
+            types.push(type_);
+        }
+    }
+    types
+}
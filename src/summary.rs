@@ -0,0 +1,139 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The prometheus crate does not provide summaries, so this implements them
+// as a MetricVec with quantiles computed over a window of the most recent
+// observations of each label combination.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+use prometheus_exporter::prometheus::{
+    core::{Desc, Describer, Metric, MetricVec, MetricVecBuilder},
+    proto, Opts, Result,
+};
+
+// The number of recent observations used to compute quantiles.
+const WINDOW_SIZE : usize = 1024;
+
+#[derive(Clone)]
+pub struct SummaryOpts {
+    pub common_opts: Opts,
+    pub objectives: Vec<f64>,
+}
+
+impl SummaryOpts {
+    pub fn new<S1: Into<String>, S2: Into<String>>(name: S1, help: S2, objectives: Vec<f64>)
+        -> SummaryOpts
+    {
+        SummaryOpts {common_opts: Opts::new(name, help), objectives}
+    }
+}
+
+impl Describer for SummaryOpts {
+    fn describe(&self) -> Result<Desc> {
+        self.common_opts.describe()
+    }
+}
+
+struct SummaryState {
+    count: u64,
+    sum: f64,
+    window: VecDeque<f64>,
+}
+
+#[derive(Clone)]
+pub struct Summary {
+    label_pairs: Vec<proto::LabelPair>,
+    objectives: Vec<f64>,
+    state: Arc<Mutex<SummaryState>>,
+}
+
+impl Summary {
+    pub fn observe(&self, v: f64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.count += 1;
+        state.sum += v;
+        if state.window.len() == WINDOW_SIZE {
+            state.window.pop_front();
+        }
+        state.window.push_back(v);
+    }
+}
+
+impl Metric for Summary {
+    fn metric(&self) -> proto::Metric {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut sorted: Vec<f64> = state.window.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let quantiles = self.objectives.iter().map(|&q| {
+            let mut quantile = proto::Quantile::default();
+            quantile.set_quantile(q);
+            quantile.set_value(if sorted.is_empty() {
+                f64::NAN
+            } else {
+                let rank = (q * sorted.len() as f64).ceil() as usize;
+                sorted[rank.clamp(1, sorted.len()) - 1]
+            });
+            quantile
+        }).collect();
+
+        let mut summary = proto::Summary::default();
+        summary.set_sample_count(state.count);
+        summary.set_sample_sum(state.sum);
+        summary.set_quantile(quantiles);
+
+        let mut m = proto::Metric::default();
+        m.set_label(self.label_pairs.clone());
+        m.set_summary(summary);
+        m
+    }
+}
+
+#[derive(Clone)]
+pub struct SummaryVecBuilder {}
+
+impl MetricVecBuilder for SummaryVecBuilder {
+    type M = Summary;
+    type P = SummaryOpts;
+
+    fn build(&self, opts: &SummaryOpts, vals: &[&str]) -> Result<Summary> {
+        let mut label_pairs: Vec<proto::LabelPair> =
+            opts.common_opts.variable_labels.iter().zip(vals).map(|(name, value)| {
+                let mut label_pair = proto::LabelPair::default();
+                label_pair.set_name(name.clone());
+                label_pair.set_value(value.to_string());
+                label_pair
+            }).collect();
+        label_pairs.sort();
+        Ok(Summary {
+            label_pairs,
+            objectives: opts.objectives.clone(),
+            state: Arc::new(Mutex::new(SummaryState {
+                count: 0,
+                sum: 0.0,
+                window: VecDeque::with_capacity(WINDOW_SIZE),
+            })),
+        })
+    }
+}
+
+pub type SummaryVec = MetricVec<SummaryVecBuilder>;
+
+pub fn new_summary_vec(opts: SummaryOpts, label_names: &[&str]) -> Result<SummaryVec> {
+    let mut opts = opts;
+    opts.common_opts.variable_labels = label_names.iter().map(|s| s.to_string()).collect();
+    MetricVec::create(proto::MetricType::SUMMARY, SummaryVecBuilder {}, opts)
+}
@@ -0,0 +1,103 @@
+// This file is part of the dcache-kafka-exporter project.
+// Copyright (C) 2024  Petter A. Urkedal
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use log::{warn};
+
+// Limits a class of log messages to a maximum number per interval.  The
+// number of suppressed messages is reported once the interval has passed,
+// by the next call of either allow or flush.
+pub struct LogThrottle {
+    what: &'static str,
+    max_per_interval: u32,
+    interval: Duration,
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    interval_start: Instant,
+    emitted: u32,
+    suppressed: u64,
+}
+
+impl LogThrottle {
+    pub fn new(what: &'static str, max_per_interval: u32, interval: Duration) -> LogThrottle {
+        LogThrottle {
+            what,
+            max_per_interval,
+            interval,
+            state: Mutex::new(ThrottleState {
+                interval_start: Instant::now(),
+                emitted: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    // Returns true if the caller may emit the next log message.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.end_interval_if_due(&mut state);
+        if state.emitted < self.max_per_interval {
+            state.emitted += 1;
+            true
+        } else {
+            state.suppressed += 1;
+            false
+        }
+    }
+
+    // Reports the suppressed messages if the interval has passed.  This
+    // should be called periodically, so that the report does not wait for
+    // the next message.
+    pub fn flush(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.end_interval_if_due(&mut state);
+    }
+
+    fn end_interval_if_due(&self, state: &mut ThrottleState) {
+        if state.interval_start.elapsed() < self.interval {
+            return;
+        }
+        if state.suppressed > 0 {
+            warn!("Suppressed {} {} in the last {}s.",
+                  state.suppressed, self.what, self.interval.as_secs());
+        }
+        state.interval_start = Instant::now();
+        state.emitted = 0;
+        state.suppressed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn flush() {
+        let throttle = LogThrottle::new("test messages", 1, Duration::from_millis(50));
+        assert!(throttle.allow());
+        assert!(!throttle.allow());
+        throttle.flush();
+        assert_eq!(throttle.state.lock().unwrap().suppressed, 1);
+        thread::sleep(Duration::from_millis(60));
+        throttle.flush();
+        assert_eq!(throttle.state.lock().unwrap().suppressed, 0);
+        assert!(throttle.allow());
+    }
+}